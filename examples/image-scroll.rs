@@ -12,8 +12,8 @@ fn main() -> layer_shell_wgpu_egui::Result {
 
     layer_shell_wgpu_egui::run_layer(
         options,
-        Box::new(|egui_context| {
-            egui_extras::install_image_loaders(&egui_context);
+        Box::new(|cc| {
+            egui_extras::install_image_loaders(&cc.egui_ctx);
             Ok(Box::<MyApp>::default())
         }),
     )
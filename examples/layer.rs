@@ -13,9 +13,9 @@ fn main() -> layer_shell_wgpu_egui::Result {
 
     layer_shell_wgpu_egui::run_layer(
         options,
-        Box::new(|egui_context| {
+        Box::new(|cc| {
             // This gives us image support:
-            egui_extras::install_image_loaders(&egui_context);
+            egui_extras::install_image_loaders(&cc.egui_ctx);
             Ok(Box::<MyApp>::default())
         }),
     )
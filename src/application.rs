@@ -14,18 +14,18 @@ pub struct WgpuLayerShellApp {
 }
 
 impl WgpuLayerShellApp {
-    pub fn new(layer_shell_options: LayerShellOptions, app_creator: AppCreator) -> Self {
+    pub fn new(layer_shell_options: LayerShellOptions, app_creator: AppCreator) -> Result<Self> {
         let event_loop = EventLoop::try_new().expect("Could not create event loop.");
-        let layer_shell_state = WgpuLayerShellState::new(event_loop.handle(), layer_shell_options);
+        let layer_shell_state = WgpuLayerShellState::new(event_loop.handle(), layer_shell_options)?;
 
-        Self {
+        Ok(Self {
             // TODO: find better way to handle this potential error
             application: RefCell::new(
-                app_creator(&layer_shell_state.egui_state.context()).expect("could not create app"),
+                app_creator(&layer_shell_state.creation_context()).expect("could not create app"),
             ),
             event_loop,
             layer_shell_state,
-        }
+        })
     }
 
     pub fn run(&mut self) -> Result {
@@ -36,6 +36,7 @@ impl WgpuLayerShellApp {
                     &mut self.layer_shell_state,
                 )
                 .unwrap();
+            self.layer_shell_state.poll_screenshots();
 
             if self.layer_shell_state.should_draw() {
                 let mut application = self.application.borrow_mut();
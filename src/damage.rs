@@ -0,0 +1,250 @@
+use egui::{
+    epaint::{ClippedShape, Shape},
+    Color32, Rect, Stroke, TextureId, TexturesDelta,
+};
+
+/// More regions than this get merged into their bounding rectangle.
+const MAX_DAMAGE_REGIONS: usize = 16;
+
+/// Parts of the surface that changed since the last frame.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Damage {
+    /// Nothing changed, presenting the frame can be skipped.
+    None,
+    /// Everything has to be redrawn, e.g. after a resize.
+    Full,
+    /// Bounding rectangles of the changed shapes, in points.
+    Regions(Vec<Rect>),
+}
+
+/// Compares the shapes of consecutive frames to find out what changed.
+#[derive(Default)]
+pub(crate) struct DamageTracker {
+    previous_shapes: Vec<ClippedShape>,
+    /// The whole buffer gets cleared with it, `None` before the first frame.
+    previous_clear_color: Option<[f32; 4]>,
+    needs_full_damage: bool,
+}
+
+impl DamageTracker {
+    /// Makes the next frame fully damaged, regardless of its shapes.
+    pub(crate) fn invalidate(&mut self) {
+        self.needs_full_damage = true;
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        shapes: &[ClippedShape],
+        textures_delta: &TexturesDelta,
+        clear_color: [f32; 4],
+    ) -> Damage {
+        let clear_color_changed =
+            self.previous_clear_color.replace(clear_color) != Some(clear_color);
+        let damage = if std::mem::take(&mut self.needs_full_damage) || clear_color_changed {
+            Damage::Full
+        } else if !textures_delta.is_empty() {
+            // any shape could be using the changed textures
+            Damage::Full
+        } else {
+            let regions = changed_regions(&self.previous_shapes, shapes);
+            if regions.is_empty() {
+                Damage::None
+            } else {
+                Damage::Regions(regions)
+            }
+        };
+
+        self.previous_shapes = shapes.to_vec();
+
+        damage
+    }
+}
+
+fn changed_regions(previous: &[ClippedShape], current: &[ClippedShape]) -> Vec<Rect> {
+    let mut regions: Vec<Rect> = Vec::new();
+
+    for index in 0..previous.len().max(current.len()) {
+        let previous = previous.get(index);
+        let current = current.get(index);
+
+        if previous == current && !is_always_changed(current) {
+            continue;
+        }
+
+        for shape in [previous, current].into_iter().flatten() {
+            let rect = shape
+                .clip_rect
+                .intersect(shape.shape.visual_bounding_rect());
+            if rect.is_positive() {
+                add_region(&mut regions, rect);
+            }
+        }
+    }
+
+    if regions.len() > MAX_DAMAGE_REGIONS {
+        let bounds = regions.iter().fold(Rect::NOTHING, |a, b| a.union(*b));
+        regions = vec![bounds];
+    }
+
+    regions
+}
+
+/// Paint callbacks can draw anything, and native textures registered by the app may be written
+/// to without egui knowing, so both are always considered changed.
+fn is_always_changed(shape: Option<&ClippedShape>) -> bool {
+    shape.is_some_and(|shape| {
+        matches!(shape.shape, Shape::Callback(_))
+            || matches!(shape.shape.texture_id(), TextureId::User(_))
+    })
+}
+
+/// Adds a region, merging it with all regions it overlaps.
+fn add_region(regions: &mut Vec<Rect>, mut rect: Rect) {
+    while let Some(index) = regions.iter().position(|region| region.intersects(rect)) {
+        rect = rect.union(regions.swap_remove(index));
+    }
+    regions.push(rect);
+}
+
+/// Shapes outlining the damaged regions, for visualizing them on top of the frame.
+pub(crate) fn debug_shapes(damage: &Damage, screen_rect: Rect) -> Vec<ClippedShape> {
+    let regions = match damage {
+        Damage::None => return Vec::new(),
+        Damage::Full => vec![screen_rect],
+        Damage::Regions(regions) => regions.clone(),
+    };
+
+    regions
+        .into_iter()
+        .flat_map(|rect| {
+            [
+                Shape::rect_filled(rect, 0.0, Color32::from_rgba_unmultiplied(255, 0, 0, 40)),
+                Shape::rect_stroke(rect.shrink(0.5), 0.0, Stroke::new(1.0, Color32::RED)),
+            ]
+        })
+        .map(|shape| ClippedShape {
+            clip_rect: Rect::EVERYTHING,
+            shape,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use egui::{epaint::PaintCallback, pos2, vec2};
+
+    use super::*;
+
+    fn rect_shape(min: egui::Pos2) -> ClippedShape {
+        ClippedShape {
+            clip_rect: Rect::EVERYTHING,
+            shape: Shape::rect_filled(
+                Rect::from_min_size(min, vec2(10.0, 10.0)),
+                0.0,
+                Color32::RED,
+            ),
+        }
+    }
+
+    #[test]
+    fn identical_shapes_are_not_damaged() {
+        let shapes = vec![rect_shape(pos2(0.0, 0.0)), rect_shape(pos2(50.0, 50.0))];
+        assert!(changed_regions(&shapes, &shapes.clone()).is_empty());
+    }
+
+    #[test]
+    fn moved_shape_damages_old_and_new_position() {
+        let previous = [rect_shape(pos2(0.0, 0.0))];
+        let current = [rect_shape(pos2(100.0, 0.0))];
+        let regions = changed_regions(&previous, &current);
+
+        assert_eq!(regions.len(), 2);
+        assert!(regions.contains(&Rect::from_min_size(pos2(0.0, 0.0), vec2(10.0, 10.0))));
+        assert!(regions.contains(&Rect::from_min_size(pos2(100.0, 0.0), vec2(10.0, 10.0))));
+    }
+
+    #[test]
+    fn callback_shape_is_always_damaged() {
+        let rect = Rect::from_min_size(pos2(20.0, 20.0), vec2(30.0, 30.0));
+        let shapes = vec![ClippedShape {
+            clip_rect: Rect::EVERYTHING,
+            shape: Shape::Callback(PaintCallback {
+                rect,
+                callback: Arc::new(()),
+            }),
+        }];
+        assert_eq!(changed_regions(&shapes, &shapes.clone()), vec![rect]);
+    }
+
+    #[test]
+    fn native_texture_is_always_damaged() {
+        let rect = Rect::from_min_size(pos2(0.0, 0.0), vec2(64.0, 36.0));
+        let mut mesh = egui::Mesh::with_texture(TextureId::User(0));
+        mesh.add_rect_with_uv(
+            rect,
+            Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
+            Color32::WHITE,
+        );
+        let shapes = vec![ClippedShape {
+            clip_rect: Rect::EVERYTHING,
+            shape: Shape::mesh(mesh),
+        }];
+        assert_eq!(changed_regions(&shapes, &shapes.clone()), vec![rect]);
+    }
+
+    #[test]
+    fn overlapping_regions_are_merged() {
+        let mut regions = Vec::new();
+        add_region(
+            &mut regions,
+            Rect::from_min_max(pos2(0.0, 0.0), pos2(10.0, 10.0)),
+        );
+        add_region(
+            &mut regions,
+            Rect::from_min_max(pos2(20.0, 0.0), pos2(30.0, 10.0)),
+        );
+        // bridges the two
+        add_region(
+            &mut regions,
+            Rect::from_min_max(pos2(5.0, 0.0), pos2(25.0, 5.0)),
+        );
+
+        assert_eq!(
+            regions,
+            vec![Rect::from_min_max(pos2(0.0, 0.0), pos2(30.0, 10.0))]
+        );
+    }
+
+    #[test]
+    fn too_many_regions_are_merged_into_their_bounds() {
+        let current: Vec<_> = (0..MAX_DAMAGE_REGIONS + 4)
+            .map(|index| rect_shape(pos2(index as f32 * 20.0, 0.0)))
+            .collect();
+        let regions = changed_regions(&[], &current);
+
+        let last = (MAX_DAMAGE_REGIONS + 3) as f32 * 20.0;
+        assert_eq!(
+            regions,
+            vec![Rect::from_min_max(pos2(0.0, 0.0), pos2(last + 10.0, 10.0))]
+        );
+    }
+
+    #[test]
+    fn changed_clear_color_damages_everything() {
+        let shapes = vec![rect_shape(pos2(0.0, 0.0))];
+        let textures_delta = TexturesDelta::default();
+        let mut tracker = DamageTracker::default();
+
+        tracker.update(&shapes, &textures_delta, [0.0; 4]);
+        assert_eq!(
+            tracker.update(&shapes, &textures_delta, [0.0; 4]),
+            Damage::None
+        );
+        assert_eq!(
+            tracker.update(&shapes, &textures_delta, [0.0, 0.0, 0.0, 1.0]),
+            Damage::Full
+        );
+    }
+}
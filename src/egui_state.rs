@@ -1,17 +1,66 @@
-use egui::{epaint::ClippedShape, Context, FullOutput, TexturesDelta};
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use egui::{
+    epaint::{ClippedShape, ImageDelta},
+    mutex::RwLock,
+    ClippedPrimitive, Context, FullOutput, ImageData, TextureId, TexturesDelta, Vec2,
+};
 use egui_wgpu::{
     wgpu::{
-        CommandEncoder, Device, LoadOp, Operations, Queue, RenderPassColorAttachment,
-        RenderPassDescriptor, StoreOp, TextureFormat, TextureView,
+        Color, CommandBuffer, CommandEncoder, Device, LoadOp, Operations, Queue,
+        RenderPassColorAttachment, RenderPassDepthStencilAttachment, RenderPassDescriptor,
+        RenderPassTimestampWrites, StoreOp, TextureFormat, TextureView,
     },
     Renderer, ScreenDescriptor,
 };
 
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::{stats::FrameTiming, transform};
+
+/// The attachments the egui render pass draws into.
+pub struct RenderTarget<'a> {
+    /// Surface texture view, or the multisampled texture if msaa is enabled.
+    pub view: &'a TextureView,
+    /// Surface texture view the multisampled texture gets resolved into.
+    pub resolve_target: Option<&'a TextureView>,
+    /// Depth buffer for depth tested paint callbacks.
+    pub depth_view: Option<&'a TextureView>,
+}
+
+impl<'a> RenderTarget<'a> {
+    pub fn new(
+        surface_view: &'a TextureView,
+        msaa_view: Option<&'a TextureView>,
+        depth_view: Option<&'a TextureView>,
+    ) -> Self {
+        match msaa_view {
+            Some(msaa_view) => Self {
+                view: msaa_view,
+                resolve_target: Some(surface_view),
+                depth_view,
+            },
+            None => Self {
+                view: surface_view,
+                resolve_target: None,
+                depth_view,
+            },
+        }
+    }
+}
+
 pub struct State {
     context: egui::Context,
     input: egui::RawInput,
-    renderer: Renderer,
+    /// `None` if frames are rendered without wgpu.
+    renderer: Option<Arc<RwLock<Renderer>>>,
+    depth_format: Option<TextureFormat>,
     start_time: std::time::Instant,
+    /// CPU side copy of every texture managed by egui, so they can be re-uploaded
+    /// when the renderer has to be recreated after a device loss.
+    textures: HashMap<TextureId, ImageDelta>,
+    /// CPU timings of the phases of the current frame.
+    pub(crate) timing: FrameTiming,
 }
 
 impl State {
@@ -22,12 +71,6 @@ impl State {
         output_depth_format: Option<TextureFormat>,
         msaa_samples: u32,
     ) -> Self {
-        let input = egui::RawInput {
-            focused: true,
-            viewport_id: egui::ViewportId::ROOT,
-            ..Default::default()
-        };
-
         let renderer = Renderer::new(
             device,
             output_color_format,
@@ -35,6 +78,26 @@ impl State {
             msaa_samples,
         );
 
+        Self::with_renderer(context, Some(renderer), output_depth_format)
+    }
+
+    /// Creates a state without a renderer, which keeps track of the textures only until
+    /// [`State::create_renderer`] is called, if ever.
+    pub(crate) fn without_renderer(context: egui::Context) -> Self {
+        Self::with_renderer(context, None, None)
+    }
+
+    fn with_renderer(
+        context: egui::Context,
+        renderer: Option<Renderer>,
+        depth_format: Option<TextureFormat>,
+    ) -> Self {
+        let input = egui::RawInput {
+            focused: true,
+            viewport_id: egui::ViewportId::ROOT,
+            ..Default::default()
+        };
+
         // input
         //     .viewports
         //     .entry(egui::ViewportId::ROOT)
@@ -44,59 +107,109 @@ impl State {
         Self {
             context,
             input,
-            renderer,
+            renderer: renderer.map(|renderer| Arc::new(RwLock::new(renderer))),
+            depth_format,
             start_time: std::time::Instant::now(),
+            textures: HashMap::new(),
+            timing: FrameTiming::default(),
         }
     }
 
-    pub fn set_size(&mut self, width: u32, height: u32) {
-        let screen_rect = egui::Rect {
-            min: egui::Pos2 { x: 0f32, y: 0f32 },
-            max: egui::Pos2 {
-                x: width as f32,
-                y: height as f32,
-            },
-        };
-        self.input.screen_rect = Some(screen_rect);
+    /// Creates the renderer, or replaces it with one on a new device after a device loss,
+    /// and uploads all textures egui currently knows about to it.
+    pub(crate) fn create_renderer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        output_color_format: TextureFormat,
+        output_depth_format: Option<TextureFormat>,
+        msaa_samples: u32,
+    ) {
+        let mut new_renderer = Renderer::new(
+            device,
+            output_color_format,
+            output_depth_format,
+            msaa_samples,
+        );
+        for (id, image_delta) in &self.textures {
+            new_renderer.update_texture(device, queue, *id, image_delta);
+        }
+        self.depth_format = output_depth_format;
+
+        // the app may hold on to the renderer, so an existing one is replaced in place
+        match &self.renderer {
+            Some(renderer) => *renderer.write() = new_renderer,
+            None => self.renderer = Some(Arc::new(RwLock::new(new_renderer))),
+        }
+    }
+
+    /// The renderer shared with the app for paint callbacks and their resources.
+    pub(crate) fn renderer(&self) -> Option<&Arc<RwLock<Renderer>>> {
+        self.renderer.as_ref()
+    }
+
+    /// The current image of a texture, with all partial updates applied.
+    #[cfg(feature = "shm")]
+    pub(crate) fn texture(&self, id: &TextureId) -> Option<&ImageDelta> {
+        self.textures.get(id)
     }
 
     pub(crate) fn input(&mut self) -> &mut egui::RawInput {
         &mut self.input
     }
 
-    pub fn context(&self) -> &egui::Context {
-        &self.context
+    /// Time since the state was created, which egui's `input.time` counts from.
+    pub(crate) fn elapsed(&self) -> std::time::Duration {
+        self.start_time.elapsed()
     }
 
-    pub fn modifiers(&self) -> egui::Modifiers {
-        self.input.modifiers
+    pub fn context(&self) -> &egui::Context {
+        &self.context
     }
 
-    pub fn push_event(&mut self, event: egui::Event) {
-        self.input.events.push(event);
+    pub fn process_events(&mut self, run_ui: impl FnOnce(&Context)) -> FullOutput {
+        let raw_input = self.input.take();
+        self.run(raw_input, run_ui)
     }
 
-    pub fn process_events(&mut self, run_ui: impl FnOnce(&Context)) -> FullOutput {
-        // TODO: maybe we need to take input for a certain window / surface?
-        self.input.time = Some(self.start_time.elapsed().as_secs_f64());
+    /// Runs a frame of the viewport the input belongs to.
+    pub(crate) fn run(
+        &mut self,
+        mut raw_input: egui::RawInput,
+        run_ui: impl FnOnce(&Context),
+    ) -> FullOutput {
+        // the time may have been set already, e.g. by the headless renderer
+        if raw_input.time.is_none() {
+            raw_input.time = Some(self.start_time.elapsed().as_secs_f64());
+        }
 
-        let raw_input = self.input.take();
         /* if (&raw_input.events).len() > 0 {
             dbg!(&raw_input.events);
         } */
-        self.context.run(raw_input, run_ui)
+        self.timing = FrameTiming {
+            start_time: raw_input.time.unwrap_or_default(),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let full_output = self.context.run(raw_input, run_ui);
+        self.timing.run_ui = start.elapsed().as_secs_f32();
+
+        full_output
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn draw(
         &mut self,
         device: &Device,
         queue: &Queue,
         encoder: &mut CommandEncoder,
-        window_surface_view: &TextureView,
+        render_target: RenderTarget,
+        load: LoadOp<Color>,
         screen_descriptor: ScreenDescriptor,
-        shapes: Vec<ClippedShape>,
+        tris: &[ClippedPrimitive],
         textures_delta: TexturesDelta,
-    ) {
+        timestamp_writes: Option<RenderPassTimestampWrites>,
+    ) -> Vec<CommandBuffer> {
         //self.context.set_pixels_per_point(screen_descriptor.pixels_per_point);
 
         // iterate over viewport outputs
@@ -110,33 +223,186 @@ impl State {
         // this is for things like clipboard support
         //self.state.handle_platform_output(window, full_output.platform_output);
 
-        let tris = self
-            .context
-            .tessellate(shapes, self.context.pixels_per_point());
-        for (id, image_delta) in &textures_delta.set {
-            self.renderer
-                .update_texture(device, queue, *id, image_delta);
-        }
-        self.renderer
-            .update_buffers(device, queue, encoder, &tris, &screen_descriptor);
+        let start = Instant::now();
+        self.update_textures(device, queue, &textures_delta);
+        let mut renderer = self
+            .renderer
+            .as_ref()
+            .expect("drawing with wgpu requires a renderer")
+            .write();
+        let user_cmd_bufs =
+            renderer.update_buffers(device, queue, encoder, tris, &screen_descriptor);
+        self.timing.upload = start.elapsed().as_secs_f32();
+
+        let start = Instant::now();
+        let has_stencil = self
+            .depth_format
+            .is_some_and(|format| format.has_stencil_aspect());
         let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
             label: Some("egui main render pass"),
             color_attachments: &[Some(RenderPassColorAttachment {
-                view: window_surface_view,
-                resolve_target: None,
+                view: render_target.view,
+                resolve_target: render_target.resolve_target,
                 ops: Operations {
-                    load: LoadOp::Clear(egui_wgpu::wgpu::Color::TRANSPARENT),
-                    store: StoreOp::Store,
+                    load,
+                    // the multisampled texture is only needed until it got resolved
+                    store: match render_target.resolve_target {
+                        Some(_) => StoreOp::Discard,
+                        None => StoreOp::Store,
+                    },
                 },
             })],
-            depth_stencil_attachment: None,
-            timestamp_writes: None,
+            depth_stencil_attachment: render_target.depth_view.map(|view| {
+                RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.0),
+                        store: StoreOp::Discard,
+                    }),
+                    stencil_ops: has_stencil.then_some(Operations {
+                        load: LoadOp::Clear(0),
+                        store: StoreOp::Discard,
+                    }),
+                }
+            }),
+            timestamp_writes,
             occlusion_query_set: None,
         });
-        self.renderer.render(&mut rpass, &tris, &screen_descriptor);
+        renderer.render(&mut rpass, tris, &screen_descriptor);
         drop(rpass);
+        drop(renderer);
+        self.timing.render = start.elapsed().as_secs_f32();
+
+        self.free_textures(&textures_delta);
+
+        user_cmd_bufs
+    }
+
+    /// Turns the shapes into meshes in the coordinates of a buffer drawn pre-transformed with
+    /// `transform`, for a viewport of `screen_size` points.
+    pub(crate) fn tessellate(
+        &mut self,
+        shapes: Vec<ClippedShape>,
+        pixels_per_point: f32,
+        transform: Transform,
+        screen_size: Vec2,
+    ) -> Vec<ClippedPrimitive> {
+        let start = Instant::now();
+        let mut primitives = self.context.tessellate(shapes, pixels_per_point);
+        transform::primitives_to_buffer(transform, &mut primitives, screen_size);
+        self.timing.tessellate = start.elapsed().as_secs_f32();
+
+        primitives
+    }
+
+    /// Uploads new and changed textures, which has to happen even if a frame is not drawn.
+    pub(crate) fn update_textures(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        textures_delta: &TexturesDelta,
+    ) {
+        if let Some(renderer) = &self.renderer {
+            let mut renderer = renderer.write();
+            for (id, image_delta) in &textures_delta.set {
+                renderer.update_texture(device, queue, *id, image_delta);
+            }
+        }
+        self.retain_textures(textures_delta);
+    }
+
+    /// Applies new and changed textures to the CPU side copies only.
+    pub(crate) fn retain_textures(&mut self, textures_delta: &TexturesDelta) {
+        for (id, image_delta) in &textures_delta.set {
+            retain_texture(&mut self.textures, *id, image_delta);
+        }
+    }
+
+    /// Frees textures egui no longer uses, after the frame using them was rendered.
+    pub(crate) fn free_textures(&mut self, textures_delta: &TexturesDelta) {
+        if let Some(renderer) = &self.renderer {
+            let mut renderer = renderer.write();
+            for x in &textures_delta.free {
+                renderer.free_texture(x);
+            }
+        }
         for x in &textures_delta.free {
-            self.renderer.free_texture(x)
+            self.textures.remove(x);
         }
     }
 }
+
+/// Keeps the CPU side copy of a texture in sync with the delta that was just uploaded.
+fn retain_texture(
+    textures: &mut HashMap<TextureId, ImageDelta>,
+    id: TextureId,
+    image_delta: &ImageDelta,
+) {
+    let Some(pos) = image_delta.pos else {
+        textures.insert(id, image_delta.clone());
+        return;
+    };
+
+    let Some(texture) = textures.get_mut(&id) else {
+        log::warn!("partial update for unknown texture {id:?}");
+        return;
+    };
+
+    match (&mut texture.image, &image_delta.image) {
+        (ImageData::Color(image), ImageData::Color(patch)) => {
+            let image = Arc::make_mut(image);
+            copy_region(
+                &mut image.pixels,
+                image.size,
+                &patch.pixels,
+                patch.size,
+                pos,
+            );
+        }
+        (ImageData::Font(image), ImageData::Font(patch)) => {
+            copy_region(
+                &mut image.pixels,
+                image.size,
+                &patch.pixels,
+                patch.size,
+                pos,
+            );
+        }
+        _ => log::warn!("partial update for texture {id:?} changes its image type"),
+    }
+    texture.options = image_delta.options;
+}
+
+fn copy_region<T: Copy>(
+    target: &mut [T],
+    target_size: [usize; 2],
+    patch: &[T],
+    patch_size: [usize; 2],
+    [x, y]: [usize; 2],
+) {
+    let width = patch_size[0].min(target_size[0].saturating_sub(x));
+    for row in 0..patch_size[1].min(target_size[1].saturating_sub(y)) {
+        let target_start = (y + row) * target_size[0] + x;
+        let patch_start = row * patch_size[0];
+        target[target_start..target_start + width]
+            .copy_from_slice(&patch[patch_start..patch_start + width]);
+    }
+}
+
+/// Clears the color target, for drawing below egui before its render pass loads the contents.
+pub(crate) fn clear(encoder: &mut CommandEncoder, view: &TextureView, clear_color: Color) {
+    encoder.begin_render_pass(&RenderPassDescriptor {
+        label: Some("egui clear pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(clear_color),
+                store: StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+}
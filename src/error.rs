@@ -1,4 +1,4 @@
 #[derive(Debug)]
 pub enum Error {
     Wgpu(egui_wgpu::WgpuError),
-}
\ No newline at end of file
+}
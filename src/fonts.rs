@@ -0,0 +1,377 @@
+//! Loading fonts installed on the system, looked up with fontconfig.
+//!
+//! libfontconfig is loaded when the first font is looked up, so it has to be installed, which it
+//! is on practically every desktop, but it is not needed to build or start the application.
+//! egui can only draw outlines, color emoji fonts show up empty.
+//!
+//! ```no_run
+//! # let ctx = egui::Context::default();
+//! use layer_shell_wgpu_egui::fonts::{add_system_font, SystemFonts};
+//!
+//! let mut fonts = SystemFonts::default().font_definitions();
+//! add_system_font(&mut fonts, "Symbols Nerd Font", egui::FontFamily::Monospace).unwrap();
+//! ctx.set_fonts(fonts);
+//! ```
+
+use std::{
+    ffi::{CStr, CString, OsStr},
+    io,
+    os::{raw::c_int, unix::ffi::OsStrExt},
+    path::PathBuf,
+    ptr,
+};
+
+use egui::{FontData, FontDefinitions, FontFamily};
+use fontconfig_sys::{
+    constants::{FC_COLOR, FC_FAMILY, FC_FILE, FC_INDEX, FC_LANG},
+    statics::LIB_RESULT,
+    Fc, FcMatchPattern, FcPattern, FcResultMatch, FcResultNoMatch,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FontError {
+    #[error("Failed to load fontconfig: {0}")]
+    Fontconfig(String),
+    #[error("No installed font matches {0}")]
+    NotFound(String),
+    #[error("Failed to read {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+/// Which system fonts to load, see [`LayerShellOptions::system_fonts`](crate::layer_shell::LayerShellOptions::system_fonts).
+///
+/// Without libfontconfig, every font fails to load with [`FontError::Fontconfig`] and egui's
+/// bundled fonts are used.
+#[derive(Clone, Debug)]
+pub struct SystemFonts {
+    /// Use the user's default sans-serif and monospace fonts instead of egui's bundled ones,
+    /// which stay as fallbacks.
+    pub use_defaults: bool,
+    /// Languages to add fallback fonts for, as fontconfig language tags like `ja`, `ko` or `zh-cn`.
+    pub languages: Vec<String>,
+    /// Add a monochrome emoji font as fallback, if one is installed.
+    pub emoji: bool,
+    /// Font families added as fallbacks for all text, e.g. `Symbols Nerd Font`.
+    pub families: Vec<String>,
+}
+
+impl Default for SystemFonts {
+    fn default() -> Self {
+        Self {
+            use_defaults: true,
+            languages: Vec::new(),
+            emoji: true,
+            families: Vec::new(),
+        }
+    }
+}
+
+impl SystemFonts {
+    /// egui's default fonts extended with the system fonts.
+    ///
+    /// Fonts that can not be found are skipped with a warning.
+    pub fn font_definitions(&self) -> FontDefinitions {
+        let mut fonts = FontDefinitions::default();
+
+        if self.use_defaults {
+            for (pattern, family) in [
+                ("sans-serif", FontFamily::Proportional),
+                ("monospace", FontFamily::Monospace),
+            ] {
+                if let Err(error) = FontMatch::find(pattern)
+                    .and_then(|font| font.insert(&mut fonts, &[family], true))
+                {
+                    log::warn!("Failed to load default {pattern} font: {error}");
+                }
+            }
+        }
+
+        let all = [FontFamily::Proportional, FontFamily::Monospace];
+        for language in &self.languages {
+            let result = FontMatch::find(&format!("sans-serif:lang={language}")).and_then(|font| {
+                // fontconfig falls back to any font if none supports the language
+                if !font.supports_language(language) {
+                    return Err(FontError::NotFound(format!("language {language}")));
+                }
+                font.insert(&mut fonts, &all, false)
+            });
+            if let Err(error) = result {
+                log::warn!("Failed to load font for {language}: {error}");
+            }
+        }
+
+        if self.emoji {
+            let result = FontMatch::find("emoji:color=false").and_then(|font| {
+                // like for languages, any font is returned if there is no monochrome emoji font
+                if !font.is_monochrome_emoji() {
+                    return Err(FontError::NotFound("monochrome emoji font".to_owned()));
+                }
+                font.insert(&mut fonts, &all, false)
+            });
+            if let Err(error) = result {
+                log::warn!("Failed to load emoji font: {error}");
+            }
+        }
+
+        for name in &self.families {
+            if let Err(error) = add_system_font(&mut fonts, name, FontFamily::Proportional)
+                .and_then(|_| add_system_font(&mut fonts, name, FontFamily::Monospace))
+            {
+                log::warn!("Failed to load font {name}: {error}");
+            }
+        }
+
+        fonts
+    }
+
+    /// Loads the fonts into the context, which takes effect on the next frame.
+    pub fn install(&self, ctx: &egui::Context) {
+        ctx.set_fonts(self.font_definitions());
+    }
+}
+
+/// Adds an installed font family as the last fallback of `family`.
+///
+/// `family` may be a [`FontFamily::Name`] which does not exist yet, e.g. for an icon font
+/// that is only used explicitly.
+pub fn add_system_font(
+    fonts: &mut FontDefinitions,
+    name: &str,
+    family: FontFamily,
+) -> Result<(), FontError> {
+    let font = FontMatch::find(name)?;
+    // fontconfig returns its default font if nothing matches
+    if !font.has_family(name) {
+        return Err(FontError::NotFound(name.to_owned()));
+    }
+
+    font.insert(fonts, &[family], false)
+}
+
+/// The best font fontconfig knows for a pattern.
+struct FontMatch {
+    path: PathBuf,
+    index: u32,
+    families: Vec<String>,
+    languages: Vec<String>,
+    color: bool,
+}
+
+impl FontMatch {
+    fn find(pattern: &str) -> Result<Self, FontError> {
+        let fc = fontconfig()?;
+        let not_found = || FontError::NotFound(pattern.to_owned());
+        let name = CString::new(pattern).map_err(|_| not_found())?;
+
+        // SAFETY: the patterns are valid until they are destroyed, after their last use
+        unsafe {
+            let query = (fc.FcNameParse)(name.as_ptr().cast());
+            if query.is_null() {
+                return Err(not_found());
+            }
+            // fill in the user's configuration and the defaults, like fc-match does
+            (fc.FcConfigSubstitute)(ptr::null_mut(), query, FcMatchPattern);
+            (fc.FcDefaultSubstitute)(query);
+            let mut result = FcResultNoMatch;
+            let font = (fc.FcFontMatch)(ptr::null_mut(), query, &mut result);
+            (fc.FcPatternDestroy)(query);
+            if font.is_null() {
+                return Err(not_found());
+            }
+
+            let found = Self::from_pattern(fc, font);
+            (fc.FcPatternDestroy)(font);
+            found.ok_or_else(not_found)
+        }
+    }
+
+    /// Reads the properties of a matched font, `None` if it has no file.
+    ///
+    /// # Safety
+    ///
+    /// `font` has to be a valid pattern.
+    unsafe fn from_pattern(fc: &Fc, font: *mut FcPattern) -> Option<Self> {
+        let path = get_string(fc, font, FC_FILE, 0)?;
+        let mut index = 0;
+        (fc.FcPatternGetInteger)(font, FC_INDEX.as_ptr(), 0, &mut index);
+        let mut color = 0;
+        (fc.FcPatternGetBool)(font, FC_COLOR.as_ptr(), 0, &mut color);
+
+        Some(Self {
+            path: PathBuf::from(OsStr::from_bytes(path.to_bytes())),
+            index: u32::try_from(index).unwrap_or(0),
+            families: (0..)
+                .map_while(|n| get_string(fc, font, FC_FAMILY, n))
+                .map(|family| family.to_string_lossy().into_owned())
+                .collect(),
+            languages: get_languages(fc, font),
+            color: color != 0,
+        })
+    }
+
+    fn has_family(&self, name: &str) -> bool {
+        self.families
+            .iter()
+            .any(|family| family.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether the font supports the language, `zh` is supported by fonts for `zh-cn`.
+    fn supports_language(&self, language: &str) -> bool {
+        self.languages.iter().any(|supported| {
+            supported.eq_ignore_ascii_case(language)
+                || supported
+                    .split_once('-')
+                    .is_some_and(|(base, _)| base.eq_ignore_ascii_case(language))
+        })
+    }
+
+    fn is_monochrome_emoji(&self) -> bool {
+        !self.color
+            && self
+                .families
+                .iter()
+                .any(|family| family.to_ascii_lowercase().contains("emoji"))
+    }
+
+    /// Loads the font, unless it is loaded already, and adds it to the families.
+    fn insert(
+        self,
+        fonts: &mut FontDefinitions,
+        families: &[FontFamily],
+        first: bool,
+    ) -> Result<(), FontError> {
+        let key = format!("{}#{}", self.path.display(), self.index);
+        if !fonts.font_data.contains_key(&key) {
+            let font = std::fs::read(&self.path).map_err(|source| FontError::Io {
+                path: self.path,
+                source,
+            })?;
+            let mut font = FontData::from_owned(font);
+            font.index = self.index;
+            fonts.font_data.insert(key.clone(), font);
+        }
+
+        for family in families {
+            let keys = fonts.families.entry(family.clone()).or_default();
+            if keys.contains(&key) {
+                continue;
+            }
+            if first {
+                keys.insert(0, key.clone());
+            } else {
+                keys.push(key.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The fontconfig library, with the default configuration loaded.
+fn fontconfig() -> Result<&'static Fc, FontError> {
+    let fc = LIB_RESULT
+        .as_ref()
+        .map_err(|error| FontError::Fontconfig(error.to_string()))?;
+    // SAFETY: loads the configuration on the first call only
+    if unsafe { (fc.FcInit)() } == 0 {
+        return Err(FontError::Fontconfig(
+            "the configuration could not be loaded".to_owned(),
+        ));
+    }
+    Ok(fc)
+}
+
+/// The `n`th string of a property, which lives as long as the pattern.
+///
+/// # Safety
+///
+/// `pattern` has to be a valid pattern.
+unsafe fn get_string<'a>(
+    fc: &Fc,
+    pattern: *mut FcPattern,
+    object: &CStr,
+    n: c_int,
+) -> Option<&'a CStr> {
+    let mut value = ptr::null_mut();
+    ((fc.FcPatternGetString)(pattern, object.as_ptr(), n, &mut value) == FcResultMatch)
+        .then(|| CStr::from_ptr(value.cast()))
+}
+
+/// The languages a font supports.
+///
+/// # Safety
+///
+/// `pattern` has to be a valid pattern.
+unsafe fn get_languages(fc: &Fc, pattern: *mut FcPattern) -> Vec<String> {
+    let mut lang_set = ptr::null_mut();
+    if (fc.FcPatternGetLangSet)(pattern, FC_LANG.as_ptr(), 0, &mut lang_set) != FcResultMatch {
+        return Vec::new();
+    }
+    let set = (fc.FcLangSetGetLangs)(lang_set);
+    if set.is_null() {
+        return Vec::new();
+    }
+
+    let mut languages = Vec::new();
+    let list = (fc.FcStrListCreate)(set);
+    if !list.is_null() {
+        loop {
+            let language = (fc.FcStrListNext)(list);
+            if language.is_null() {
+                break;
+            }
+            languages.push(
+                CStr::from_ptr(language.cast())
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        (fc.FcStrListDone)(list);
+    }
+    (fc.FcStrSetDestroy)(set);
+    languages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn font(families: &[&str], languages: &[&str], color: bool) -> FontMatch {
+        FontMatch {
+            path: PathBuf::from("/usr/share/fonts/font.ttf"),
+            index: 0,
+            families: families.iter().map(|&family| family.to_owned()).collect(),
+            languages: languages
+                .iter()
+                .map(|&language| language.to_owned())
+                .collect(),
+            color,
+        }
+    }
+
+    #[test]
+    fn has_family_ignores_case() {
+        let font = font(&["Symbols Nerd Font", "Symbols NF"], &[], false);
+        assert!(font.has_family("symbols nerd font"));
+        assert!(font.has_family("Symbols NF"));
+        assert!(!font.has_family("Symbols"));
+    }
+
+    #[test]
+    fn supports_language_matches_territories() {
+        let font = font(&["Noto Sans CJK JP"], &["ja", "zh-cn", "ko"], false);
+        assert!(font.supports_language("JA"));
+        assert!(font.supports_language("zh-CN"));
+        assert!(font.supports_language("zh"));
+        assert!(!font.supports_language("zh-tw"));
+        assert!(!font.supports_language("de"));
+    }
+
+    #[test]
+    fn monochrome_emoji_needs_an_emoji_family_without_color() {
+        assert!(font(&["Noto Emoji"], &[], false).is_monochrome_emoji());
+        assert!(!font(&["Noto Color Emoji"], &[], true).is_monochrome_emoji());
+        assert!(!font(&["DejaVu Sans"], &[], false).is_monochrome_emoji());
+    }
+}
@@ -0,0 +1,291 @@
+//! Running an [`App`] without a Wayland compositor, rendering into an offscreen texture.
+//!
+//! This is meant for snapshot tests of the UI, e.g. on CI machines without a GPU. Comparing
+//! frames with reference images needs the `snapshot` feature:
+//!
+//! ```no_run
+//! use layer_shell_wgpu_egui::headless::{HeadlessOptions, HeadlessRenderer};
+//!
+//! let mut renderer = HeadlessRenderer::new(
+//!     HeadlessOptions {
+//!         width: 400,
+//!         height: 30,
+//!         force_fallback_adapter: true,
+//!         ..Default::default()
+//!     },
+//!     Box::new(|_| Ok(Box::new(MyBar::default()))),
+//! )
+//! .unwrap();
+//!
+//! let frame = renderer.run([egui::Event::PointerMoved(egui::pos2(10.0, 10.0))]);
+//! # #[cfg(feature = "snapshot")]
+//! layer_shell_wgpu_egui::headless::compare_with_reference(&frame, "tests/snapshots/bar.png", 2)
+//!     .unwrap();
+//! # #[derive(Default)] struct MyBar;
+//! # impl layer_shell_wgpu_egui::App for MyBar { fn update(&mut self, _: &egui::Context) {} }
+//! ```
+
+#[cfg(feature = "snapshot")]
+mod snapshot;
+
+use std::{sync::Arc, time::Instant};
+
+use egui::ColorImage;
+use egui_wgpu::{RenderState, ScreenDescriptor, WgpuError};
+use wayland_client::protocol::wl_output::Transform;
+use wgpu::{
+    Backends, CommandEncoderDescriptor, CompositeAlphaMode, Extent3d, Instance, InstanceDescriptor,
+    LoadOp, Maintain, PowerPreference, Texture, TextureDescriptor, TextureDimension, TextureFormat,
+    TextureUsages,
+};
+
+use crate::{
+    egui_state::{self, RenderTarget},
+    readback::TextureReadback,
+    stats::FrameTiming,
+    wgpu_state::{self, WgpuOptions, WgpuSetup},
+    App, AppCreator, CreationContext, Error, Frame, Result,
+};
+
+#[cfg(feature = "snapshot")]
+pub use snapshot::{compare_with_reference, save_png, SnapshotError, UPDATE_SNAPSHOTS_ENV};
+
+const TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+pub struct HeadlessOptions {
+    /// Width of the rendered frames in pixels.
+    pub width: u32,
+    /// Height of the rendered frames in pixels.
+    pub height: u32,
+    pub pixels_per_point: f32,
+    /// Time that passes between two frames, in seconds, so animations are reproducible.
+    pub frame_time: f64,
+    /// Backends wgpu may pick an adapter from, can be overridden with `WGPU_BACKEND`.
+    pub backends: Backends,
+    /// Only consider software adapters, e.g. for running on machines without a GPU.
+    pub force_fallback_adapter: bool,
+    /// Prefer the adapter whose name contains this, can be overridden with `WGPU_ADAPTER_NAME`.
+    pub adapter_name: Option<String>,
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 600,
+            pixels_per_point: 1.0,
+            frame_time: 1.0 / 60.0,
+            backends: Backends::all(),
+            force_fallback_adapter: false,
+            adapter_name: None,
+        }
+    }
+}
+
+/// Renders an [`App`] into an offscreen texture and reads the frames back.
+pub struct HeadlessRenderer {
+    app: Box<dyn App>,
+    egui_state: egui_state::State,
+    // also keeps the enumerated adapters alive, dropping them can tear down the
+    // shared display connection of the GL backend
+    render_state: RenderState,
+    frame: Frame,
+    texture: Texture,
+    pixels_per_point: f32,
+    frame_time: f64,
+    frame_count: u64,
+}
+
+impl HeadlessRenderer {
+    pub fn new(options: HeadlessOptions, app_creator: AppCreator) -> Result<Self> {
+        let selection = WgpuOptions {
+            msaa_samples: 1,
+            depth_format: None,
+            backends: options.backends,
+            power_preference: PowerPreference::default(),
+            force_fallback_adapter: options.force_fallback_adapter,
+            adapter_name: options.adapter_name,
+            setup: WgpuSetup::CreateNew,
+            post_render: false,
+        }
+        .with_env_overrides();
+
+        let instance = Instance::new(InstanceDescriptor {
+            backends: selection.backends,
+            ..Default::default()
+        });
+        let adapter = wgpu_state::request_adapter(&instance, &selection, None)
+            .map_err(|_| Error::Wgpu(WgpuError::NoSuitableAdapterFound))?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&Default::default(), None))
+            .map_err(|error| Error::Wgpu(WgpuError::RequestDeviceError(error)))?;
+        let (device, queue) = (Arc::new(device), Arc::new(queue));
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("egui headless texture"),
+            size: Extent3d {
+                width: options.width.max(1),
+                height: options.height.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TEXTURE_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        let mut egui_state =
+            egui_state::State::new(egui::Context::default(), &device, TEXTURE_FORMAT, None, 1);
+        egui_state
+            .input()
+            .viewports
+            .entry(egui::ViewportId::ROOT)
+            .or_default()
+            .native_pixels_per_point = Some(options.pixels_per_point);
+
+        let render_state = RenderState {
+            adapter: Arc::new(adapter),
+            available_adapters: instance.enumerate_adapters(selection.backends).into(),
+            device,
+            queue,
+            target_format: TEXTURE_FORMAT,
+            renderer: Arc::clone(egui_state.renderer().expect("created with a renderer")),
+        };
+        let creation_context = CreationContext {
+            egui_ctx: egui_state.context().clone(),
+            wgpu_render_state: Some(render_state.clone()),
+            layer_surface: None,
+            shell_mode: None,
+        };
+        let app = app_creator(&creation_context)?;
+
+        Ok(Self {
+            app,
+            egui_state,
+            frame: Frame::new(Some(render_state.clone())),
+            render_state,
+            texture,
+            pixels_per_point: options.pixels_per_point,
+            frame_time: options.frame_time,
+            frame_count: 0,
+        })
+    }
+
+    pub fn context(&self) -> &egui::Context {
+        self.egui_state.context()
+    }
+
+    /// Runs the app for one frame with the given input events and returns the rendered frame.
+    pub fn run(&mut self, events: impl IntoIterator<Item = egui::Event>) -> ColorImage {
+        let start = Instant::now();
+        let size_in_pixels = [self.texture.width(), self.texture.height()];
+
+        let input = self.egui_state.input();
+        input.events.extend(events);
+        input.time = Some(self.frame_count as f64 * self.frame_time);
+        input.predicted_dt = self.frame_time as f32;
+        let screen_size =
+            egui::vec2(size_in_pixels[0] as f32, size_in_pixels[1] as f32) / self.pixels_per_point;
+        input.screen_rect = Some(egui::Rect::from_min_size(egui::Pos2::ZERO, screen_size));
+        self.frame_count += 1;
+
+        let app = &mut self.app;
+        let frame = &mut self.frame;
+        let full_output = self
+            .egui_state
+            .process_events(|ctx| app.update_with_frame(ctx, frame));
+        let clear_color = wgpu_state::clear_color(
+            TEXTURE_FORMAT,
+            CompositeAlphaMode::PreMultiplied,
+            app.clear_color(&self.egui_state.context().style().visuals),
+        );
+
+        let primitives = self.egui_state.tessellate(
+            full_output.shapes,
+            full_output.pixels_per_point,
+            Transform::Normal,
+            screen_size,
+        );
+
+        let view = self.texture.create_view(&Default::default());
+        let mut encoder = self
+            .render_state
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+
+        let user_cmd_bufs = self.egui_state.draw(
+            &self.render_state.device,
+            &self.render_state.queue,
+            &mut encoder,
+            RenderTarget::new(&view, None, None),
+            LoadOp::Clear(clear_color),
+            ScreenDescriptor {
+                size_in_pixels,
+                pixels_per_point: self.pixels_per_point,
+            },
+            &primitives,
+            full_output.textures_delta,
+            None,
+        );
+
+        let readback = TextureReadback::new(&self.render_state.device, &mut encoder, &self.texture);
+        self.render_state.queue.submit(
+            user_cmd_bufs
+                .into_iter()
+                .chain(std::iter::once(encoder.finish())),
+        );
+        self.frame.stats.push(FrameTiming {
+            total: start.elapsed().as_secs_f32(),
+            ..self.egui_state.timing
+        });
+
+        let readback = readback.map();
+        self.render_state.device.poll(Maintain::Wait);
+        readback
+            .try_take()
+            .expect("readback buffer was never mapped")
+            .expect("Failed to map readback buffer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fill(egui::Color32);
+
+    impl App for Fill {
+        fn update(&mut self, ctx: &egui::Context) {
+            egui::CentralPanel::default()
+                .frame(egui::Frame::none().fill(self.0))
+                .show(ctx, |_| {});
+        }
+    }
+
+    #[test]
+    fn run_renders_the_app() {
+        let color = egui::Color32::from_rgb(200, 40, 10);
+        let renderer = HeadlessRenderer::new(
+            HeadlessOptions {
+                width: 16,
+                height: 8,
+                force_fallback_adapter: true,
+                ..Default::default()
+            },
+            Box::new(move |_| Ok(Box::new(Fill(color)))),
+        );
+        let mut renderer = match renderer {
+            Ok(renderer) => renderer,
+            Err(Error::Wgpu(WgpuError::NoSuitableAdapterFound)) => {
+                eprintln!("skipped, no fallback adapter available");
+                return;
+            }
+            Err(error) => panic!("Could not create headless renderer: {error:?}"),
+        };
+
+        let frame = renderer.run([]);
+        assert_eq!(frame.size, [16, 8]);
+        assert!(frame.pixels.iter().all(|pixel| *pixel == color));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use egui::{Color32, ColorImage};
+use thiserror::Error;
+
+/// Environment variable which makes [`compare_with_reference`] write the frame as new reference.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "UPDATE_SNAPSHOTS";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Failed to access {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("Failed to decode {path}: {source}")]
+    Decode {
+        path: PathBuf,
+        source: png::DecodingError,
+    },
+    #[error("Failed to encode png: {0}")]
+    Encode(#[from] png::EncodingError),
+    #[error("Unsupported png format in {0}, expected 8 bit color")]
+    UnsupportedFormat(PathBuf),
+    #[error("Frame size {actual:?} does not match reference size {expected:?}")]
+    SizeMismatch {
+        expected: [usize; 2],
+        actual: [usize; 2],
+    },
+    #[error(
+        "{differing_pixels} pixels differ by more than {tolerance}, by up to {max_difference}"
+    )]
+    Mismatch {
+        differing_pixels: usize,
+        max_difference: u8,
+        tolerance: u8,
+    },
+}
+
+/// Compares a frame against a reference PNG, allowing each color channel to differ
+/// by up to `tolerance`.
+///
+/// If the [`UPDATE_SNAPSHOTS_ENV`] environment variable is set, the frame is written
+/// as the new reference instead.
+pub fn compare_with_reference(
+    frame: &ColorImage,
+    reference: impl AsRef<Path>,
+    tolerance: u8,
+) -> Result<(), SnapshotError> {
+    let reference = reference.as_ref();
+    if std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some() {
+        return save_png(frame, reference);
+    }
+
+    compare_images(&load_png(reference)?, frame, tolerance)
+}
+
+fn compare_images(
+    expected: &ColorImage,
+    frame: &ColorImage,
+    tolerance: u8,
+) -> Result<(), SnapshotError> {
+    if expected.size != frame.size {
+        return Err(SnapshotError::SizeMismatch {
+            expected: expected.size,
+            actual: frame.size,
+        });
+    }
+
+    let mut differing_pixels = 0;
+    let mut max_difference = 0;
+    for (expected, actual) in expected.pixels.iter().zip(&frame.pixels) {
+        let difference = (0..4)
+            .map(|channel| expected[channel].abs_diff(actual[channel]))
+            .max()
+            .unwrap_or_default();
+        if difference > tolerance {
+            differing_pixels += 1;
+        }
+        max_difference = max_difference.max(difference);
+    }
+
+    if differing_pixels > 0 {
+        return Err(SnapshotError::Mismatch {
+            differing_pixels,
+            max_difference,
+            tolerance,
+        });
+    }
+
+    Ok(())
+}
+
+/// Writes a frame as RGBA PNG, e.g. to create a reference image.
+pub fn save_png(frame: &ColorImage, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+    let path = path.as_ref();
+    let io_error = |source| SnapshotError::Io {
+        path: path.to_owned(),
+        source,
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    let file = File::create(path).map_err(io_error)?;
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        frame.width() as u32,
+        frame.height() as u32,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let rgba: Vec<u8> = frame
+        .pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_srgba_unmultiplied())
+        .collect();
+    encoder.write_header()?.write_image_data(&rgba)?;
+
+    Ok(())
+}
+
+fn load_png(path: &Path) -> Result<ColorImage, SnapshotError> {
+    let file = File::open(path).map_err(|source| SnapshotError::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    let decode_error = |source| SnapshotError::Decode {
+        path: path.to_owned(),
+        source,
+    };
+
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(decode_error)?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(decode_error)?;
+    let data = &data[..info.buffer_size()];
+
+    let pixels: Vec<Color32> = match info.color_type {
+        png::ColorType::Rgba => data
+            .chunks_exact(4)
+            .map(|p| Color32::from_rgba_unmultiplied(p[0], p[1], p[2], p[3]))
+            .collect(),
+        png::ColorType::Rgb => data
+            .chunks_exact(3)
+            .map(|p| Color32::from_rgb(p[0], p[1], p[2]))
+            .collect(),
+        png::ColorType::GrayscaleAlpha => data
+            .chunks_exact(2)
+            .map(|p| Color32::from_rgba_unmultiplied(p[0], p[0], p[0], p[1]))
+            .collect(),
+        png::ColorType::Grayscale => data.iter().map(|p| Color32::from_gray(*p)).collect(),
+        png::ColorType::Indexed => return Err(SnapshotError::UnsupportedFormat(path.to_owned())),
+    };
+
+    Ok(ColorImage {
+        size: [info.width as usize, info.height as usize],
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(color: Color32) -> ColorImage {
+        ColorImage::new([4, 3], color)
+    }
+
+    #[test]
+    fn matching_images_are_equal() {
+        let frame = image(Color32::from_rgb(10, 20, 30));
+        assert!(compare_images(&frame, &frame.clone(), 0).is_ok());
+    }
+
+    #[test]
+    fn difference_within_tolerance_matches() {
+        let expected = image(Color32::from_rgb(10, 20, 30));
+        let frame = image(Color32::from_rgb(12, 20, 29));
+        assert!(compare_images(&expected, &frame, 2).is_ok());
+    }
+
+    #[test]
+    fn difference_beyond_tolerance_mismatches() {
+        let expected = image(Color32::from_rgb(10, 20, 30));
+        let mut frame = expected.clone();
+        frame[(1, 1)] = Color32::from_rgb(10, 25, 30);
+
+        match compare_images(&expected, &frame, 2) {
+            Err(SnapshotError::Mismatch {
+                differing_pixels,
+                max_difference,
+                tolerance,
+            }) => assert_eq!((differing_pixels, max_difference, tolerance), (1, 5, 2)),
+            result => panic!("expected a mismatch, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn different_sizes_mismatch() {
+        let expected = image(Color32::BLACK);
+        let frame = ColorImage::new([3, 4], Color32::BLACK);
+
+        match compare_images(&expected, &frame, 255) {
+            Err(SnapshotError::SizeMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), ([4, 3], [3, 4]))
+            }
+            result => panic!("expected a size mismatch, got {result:?}"),
+        }
+    }
+
+    // the only test reading the environment variable, which is shared by all tests
+    #[test]
+    fn reference_is_compared_and_updated() {
+        let reference = std::env::temp_dir()
+            .join(format!("layer-shell-wgpu-egui-{}", std::process::id()))
+            .join("reference.png");
+        let first = image(Color32::from_rgb(200, 100, 0));
+        let second = image(Color32::from_rgb(0, 100, 200));
+
+        save_png(&first, &reference).unwrap();
+        assert!(compare_with_reference(&first, &reference, 0).is_ok());
+        assert!(compare_with_reference(&second, &reference, 0).is_err());
+
+        std::env::set_var(UPDATE_SNAPSHOTS_ENV, "1");
+        let updated = compare_with_reference(&second, &reference, 0);
+        std::env::remove_var(UPDATE_SNAPSHOTS_ENV);
+        assert!(updated.is_ok());
+        assert!(compare_with_reference(&second, &reference, 0).is_ok());
+
+        std::fs::remove_dir_all(reference.parent().unwrap()).unwrap();
+    }
+}
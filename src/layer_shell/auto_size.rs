@@ -0,0 +1,139 @@
+/// Sizes a layer surface to fit its content, as measured by [`egui::Context::used_rect`] after
+/// the app laid it out.
+///
+/// Panels fill the surface along at least one axis and the central panel along both, so content
+/// that should shrink the surface goes into an [`egui::Area`] or [`egui::Window`], or a panel
+/// along the axis it fits. Axes the surface is stretched along, anchored to both edges with a
+/// size of `0`, keep the size the compositor gives them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoSize {
+    pub min_size: [u32; 2],
+    pub max_size: [u32; 2],
+    /// How often a frame gets laid out again at the size its content asked for, before it is
+    /// shown anyway.
+    ///
+    /// Every pass calls [`App::update`](crate::App::update) again, so it runs up to
+    /// `max_passes + 1` times per frame while the size changes. Only the first pass gets the
+    /// input events, and the output of all but the last one is discarded.
+    pub max_passes: u32,
+}
+
+impl Default for AutoSize {
+    fn default() -> Self {
+        Self {
+            min_size: [1, 1],
+            max_size: [u32::MAX, u32::MAX],
+            max_passes: 2,
+        }
+    }
+}
+
+impl AutoSize {
+    /// The size to lay out the next pass at, given the size the last one was laid out at and the
+    /// size it used.
+    pub(crate) fn fit(&self, used: egui::Vec2, layout: [u32; 2], requested: [u32; 2]) -> [u32; 2] {
+        let used = [used.x, used.y];
+        [0, 1].map(|axis| {
+            if requested[axis] == 0 {
+                // stretched by the compositor
+                return layout[axis];
+            }
+            (used[axis].ceil() as u32)
+                .min(self.max_size[axis])
+                .max(self.min_size[axis].max(1))
+        })
+    }
+
+    /// The initial size of a surface, which must not be `0` unless it is stretched along the
+    /// axis.
+    pub(crate) fn initial_size(&self, size: [u32; 2], stretched: [bool; 2]) -> [u32; 2] {
+        [0, 1].map(|axis| match size[axis] {
+            0 if stretched[axis] => 0,
+            length => length
+                .min(self.max_size[axis])
+                .max(self.min_size[axis].max(1)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::vec2;
+
+    use super::*;
+
+    fn auto_size() -> AutoSize {
+        AutoSize {
+            min_size: [20, 10],
+            max_size: [200, 100],
+            ..AutoSize::default()
+        }
+    }
+
+    #[test]
+    fn fit_uses_the_used_size() {
+        assert_eq!(
+            auto_size().fit(vec2(50.2, 30.0), [100, 100], [100, 100]),
+            [51, 30]
+        );
+    }
+
+    #[test]
+    fn fit_clamps_to_min_and_max_size() {
+        let auto_size = auto_size();
+        assert_eq!(
+            auto_size.fit(vec2(5.0, 2.0), [100, 100], [100, 100]),
+            [20, 10]
+        );
+        assert_eq!(
+            auto_size.fit(vec2(500.0, 300.0), [100, 100], [100, 100]),
+            [200, 100]
+        );
+    }
+
+    #[test]
+    fn fit_keeps_stretched_axes() {
+        assert_eq!(
+            auto_size().fit(vec2(50.0, 30.0), [1920, 100], [0, 100]),
+            [1920, 30]
+        );
+        assert_eq!(
+            auto_size().fit(vec2(500.0, 300.0), [100, 1080], [100, 0]),
+            [200, 1080]
+        );
+    }
+
+    #[test]
+    fn fit_is_never_zero() {
+        let auto_size = AutoSize {
+            min_size: [0, 0],
+            ..AutoSize::default()
+        };
+        assert_eq!(
+            auto_size.fit(vec2(0.0, 0.0), [100, 100], [100, 100]),
+            [1, 1]
+        );
+    }
+
+    #[test]
+    fn initial_size_clamps_to_min_and_max_size() {
+        let auto_size = auto_size();
+        assert_eq!(auto_size.initial_size([5, 500], [false, false]), [20, 100]);
+        assert_eq!(auto_size.initial_size([50, 50], [false, false]), [50, 50]);
+    }
+
+    #[test]
+    fn initial_size_keeps_zero_on_stretched_axes() {
+        assert_eq!(auto_size().initial_size([0, 0], [true, false]), [0, 10]);
+        assert_eq!(auto_size().initial_size([0, 0], [false, true]), [20, 0]);
+    }
+
+    #[test]
+    fn initial_size_is_never_zero_on_other_axes() {
+        let auto_size = AutoSize {
+            min_size: [0, 0],
+            ..AutoSize::default()
+        };
+        assert_eq!(auto_size.initial_size([0, 0], [false, false]), [1, 1]);
+    }
+}
@@ -0,0 +1,112 @@
+use egui::ViewportId;
+use smithay_client_toolkit::{
+    reexports::calloop::channel::Sender,
+    shell::wlr_layer::{Anchor, KeyboardInteractivity, Layer},
+};
+
+use super::wlr_layer::LayerSurface;
+
+/// A change to the layer surface, queued by a [`LayerSurfaceHandle`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum LayerSurfaceCommand {
+    Anchor(Anchor),
+    Size(u32, u32),
+    Margin {
+        top: i32,
+        right: i32,
+        bottom: i32,
+        left: i32,
+    },
+    ExclusiveZone(i32),
+    Layer(Layer),
+    KeyboardInteractivity(KeyboardInteractivity),
+}
+
+impl LayerSurfaceCommand {
+    pub(crate) fn apply(self, layer: &LayerSurface) {
+        match self {
+            LayerSurfaceCommand::Anchor(anchor) => layer.set_anchor(anchor),
+            LayerSurfaceCommand::Size(width, height) => layer.set_size(width, height),
+            LayerSurfaceCommand::Margin {
+                top,
+                right,
+                bottom,
+                left,
+            } => layer.set_margin(top, right, bottom, left),
+            LayerSurfaceCommand::ExclusiveZone(zone) => layer.set_exclusive_zone(zone),
+            LayerSurfaceCommand::Layer(value) => layer.set_layer(value),
+            LayerSurfaceCommand::KeyboardInteractivity(value) => {
+                layer.set_keyboard_interactivity(value)
+            }
+        }
+    }
+}
+
+/// Changes the layer surface while the app is running, see [`Frame::layer_surface`](crate::Frame::layer_surface).
+///
+/// Changes are queued and applied together on the next frame, which commits them along with
+/// its buffer. A new size only takes effect once the compositor acknowledged it with a
+/// configure, the frame after that is drawn at the new size. The handle can be cloned and sent
+/// to other threads, changes made after the app exited or the surface got removed are ignored.
+#[derive(Clone, Debug)]
+pub struct LayerSurfaceHandle {
+    sender: Sender<(ViewportId, LayerSurfaceCommand)>,
+    /// The viewport of the surface this handle changes.
+    viewport_id: ViewportId,
+}
+
+impl LayerSurfaceHandle {
+    pub(crate) fn new(
+        sender: Sender<(ViewportId, LayerSurfaceCommand)>,
+        viewport_id: ViewportId,
+    ) -> Self {
+        Self {
+            sender,
+            viewport_id,
+        }
+    }
+
+    /// The egui viewport the surface is drawn as.
+    pub fn viewport_id(&self) -> ViewportId {
+        self.viewport_id
+    }
+
+    pub fn set_anchor(&self, anchor: Anchor) {
+        self.send(LayerSurfaceCommand::Anchor(anchor));
+    }
+
+    /// A width or height of `0` makes the surface as large as the output in that direction,
+    /// which requires anchoring it to both opposite edges.
+    pub fn set_size(&self, width: u32, height: u32) {
+        self.send(LayerSurfaceCommand::Size(width, height));
+    }
+
+    pub fn set_margin(&self, top: i32, right: i32, bottom: i32, left: i32) {
+        self.send(LayerSurfaceCommand::Margin {
+            top,
+            right,
+            bottom,
+            left,
+        });
+    }
+
+    /// Space reserved at the anchored edge, `-1` to ignore the zones of other surfaces.
+    pub fn set_exclusive_zone(&self, zone: i32) {
+        self.send(LayerSurfaceCommand::ExclusiveZone(zone));
+    }
+
+    pub fn set_layer(&self, layer: Layer) {
+        self.send(LayerSurfaceCommand::Layer(layer));
+    }
+
+    pub fn set_keyboard_interactivity(&self, keyboard_interactivity: KeyboardInteractivity) {
+        self.send(LayerSurfaceCommand::KeyboardInteractivity(
+            keyboard_interactivity,
+        ));
+    }
+
+    fn send(&self, command: LayerSurfaceCommand) {
+        // the event loop is gone once the app exited
+        let _ = self.sender.send((self.viewport_id, command));
+    }
+}
@@ -15,12 +15,15 @@ impl KeyboardHandler for WgpuLayerShellState {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
         _keyboard: &wayland_client::protocol::wl_keyboard::WlKeyboard,
-        _surface: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _serial: u32,
         _raw: &[u32],
         _keysyms: &[smithay_client_toolkit::seat::keyboard::Keysym],
     ) {
-        let input = self.egui_state.input();
+        self.keyboard_focus = Some(surface.clone());
+        let Some(input) = self.focused_input() else {
+            return;
+        };
         input.focused = true;
         // todo: this should probably be in surface enter?
         input.events.push(egui::Event::WindowFocused(true));
@@ -31,10 +34,13 @@ impl KeyboardHandler for WgpuLayerShellState {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
         _keyboard: &wayland_client::protocol::wl_keyboard::WlKeyboard,
-        _surface: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _serial: u32,
     ) {
-        let input = self.egui_state.input();
+        self.keyboard_focus = None;
+        let Some((input, _)) = self.input_mut(surface) else {
+            return;
+        };
         input.focused = false;
         // todo: this should probably be in surface enter?
         input.events.push(egui::Event::WindowFocused(false));
@@ -48,7 +54,9 @@ impl KeyboardHandler for WgpuLayerShellState {
         _serial: u32,
         event: smithay_client_toolkit::seat::keyboard::KeyEvent,
     ) {
-        handle_key_press(event, true, self.egui_state.input());
+        if let Some(input) = self.focused_input() {
+            handle_key_press(event, true, input);
+        }
     }
 
     fn release_key(
@@ -59,7 +67,9 @@ impl KeyboardHandler for WgpuLayerShellState {
         _serial: u32,
         event: smithay_client_toolkit::seat::keyboard::KeyEvent,
     ) {
-        handle_key_press(event, false, self.egui_state.input());
+        if let Some(input) = self.focused_input() {
+            handle_key_press(event, false, input);
+        }
     }
 
     fn update_modifiers(
@@ -71,13 +81,17 @@ impl KeyboardHandler for WgpuLayerShellState {
         modifiers: smithay_client_toolkit::seat::keyboard::Modifiers,
         _layout: u32,
     ) {
-        self.egui_state.input().modifiers = Modifiers {
+        let modifiers = Modifiers {
             alt: modifiers.alt,
             ctrl: modifiers.ctrl,
             shift: modifiers.shift,
             mac_cmd: false, // this is linux only
             command: modifiers.ctrl,
         };
+        // pointer events on the other surfaces use them too
+        for surface in &mut self.surfaces {
+            surface.input.modifiers = modifiers;
+        }
     }
 }
 
@@ -249,9 +263,9 @@ fn keysym_to_egui_key(keysym: Keysym) -> Option<egui::Key> {
 }
 
 fn is_printable_char(chr: char) -> bool {
-    let is_in_private_use_area = '\u{e000}' <= chr && chr <= '\u{f8ff}'
-        || '\u{f0000}' <= chr && chr <= '\u{ffffd}'
-        || '\u{100000}' <= chr && chr <= '\u{10fffd}';
+    let is_in_private_use_area = ('\u{e000}'..='\u{f8ff}').contains(&chr)
+        || ('\u{f0000}'..='\u{ffffd}').contains(&chr)
+        || ('\u{100000}'..='\u{10fffd}').contains(&chr);
 
     !is_in_private_use_area && !chr.is_ascii_control()
 }
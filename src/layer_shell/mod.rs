@@ -1,40 +1,142 @@
+mod auto_size;
+mod handle;
 mod keyboard_handler;
+mod output;
 mod pointer_handler;
+mod popup;
+mod presentation;
+mod surface;
+mod viewport;
+mod wlr_layer;
 
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    num::NonZeroU32,
+    rc::Rc,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
 
+pub use auto_size::AutoSize;
+pub use handle::LayerSurfaceHandle;
+pub use output::{OutputFallback, OutputSelector};
+
+use egui::{epaint::ClippedShape, FullOutput, TexturesDelta, ViewportClass, ViewportId};
 use egui_wgpu::ScreenDescriptor;
+use handle::LayerSurfaceCommand;
 use keyboard_handler::handle_key_press;
+use popup::PopupSurface;
+use presentation::PresentationState;
 use smithay_client_toolkit::{
     compositor::{CompositorHandler, CompositorState},
-    delegate_compositor, delegate_layer, delegate_output, delegate_registry, delegate_seat,
+    delegate_compositor, delegate_output, delegate_registry, delegate_seat, delegate_xdg_shell,
+    delegate_xdg_window,
+    globals::GlobalData,
     output::{OutputHandler, OutputState},
-    reexports::{calloop::LoopHandle, calloop_wayland_source::WaylandSource},
+    reexports::{
+        calloop::{channel, LoopHandle},
+        calloop_wayland_source::WaylandSource,
+    },
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
     seat::{Capability, SeatHandler, SeatState},
     shell::{
-        wlr_layer::{
-            Anchor, KeyboardInteractivity, Layer, LayerShell, LayerShellHandler, LayerSurface,
-            LayerSurfaceConfigure,
+        wlr_layer::{Anchor, KeyboardInteractivity, Layer},
+        xdg::{
+            window::{Window, WindowConfigure, WindowDecorations, WindowHandler},
+            XdgShell,
         },
         WaylandSurface,
     },
 };
+use surface::{ShellSurface, Surface, SurfaceOptions};
+use viewport::ImmediateViewports;
 use wayland_client::{
+    delegate_dispatch,
     globals::registry_queue_init,
     protocol::{wl_keyboard::WlKeyboard, wl_output, wl_pointer::WlPointer, wl_seat, wl_surface},
-    Connection, QueueHandle,
+    Connection, Proxy, QueueHandle,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::ZwlrLayerShellV1, zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+};
+use wlr_layer::{
+    LayerShell, LayerShellHandler, LayerSurface, LayerSurfaceConfigure, LayerSurfaceData,
 };
 
 use crate::{
-    egui_state::{self},
-    wgpu_state::WgpuState,
-    App,
+    damage::{self, Damage},
+    egui_state::{self, RenderTarget},
+    fonts::SystemFonts,
+    readback::TextureReadback,
+    stats::{self, FrameTiming},
+    transform,
+    wgpu_state::{SharedDevice, WgpuOptions, WgpuSetup, WgpuState},
+    App, CreationContext, Error, RenderContext, Result,
+};
+#[cfg(feature = "shm")]
+use crate::{
+    shm_state::ShmState,
+    software_renderer::SoftwareRenderer,
+    wgpu_state::{self, WgpuStateError},
 };
+#[cfg(feature = "shm")]
+use smithay_client_toolkit::{
+    delegate_shm,
+    shm::{Shm, ShmHandler},
+};
+
+/// Delay before retrying to recreate a lost device, doubled after every failed attempt.
+const DEVICE_RECOVERY_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_DEVICE_RECOVERY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Time between frames without changes if the output's refresh rate is not known.
+const SKIPPED_FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+/// How often the device is polled while waiting for screenshots to be read back.
+const SCREENSHOT_POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// How long to wait for the compositor to release a shm buffer before drawing again.
+#[cfg(feature = "shm")]
+const SHM_BUFFER_RETRY_DELAY: Duration = Duration::from_millis(2);
+
+/// Initial size of toplevel windows for options without a width or height.
+const DEFAULT_WINDOW_SIZE: [u32; 2] = [800, 600];
+
+/// Distance of the surface from the edges it is anchored to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Margin {
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub left: i32,
+}
+
+/// Space other surfaces, like windows, keep clear of at the anchored edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExclusiveZone {
+    /// Reserve this many pixels, `-1` also ignores the zones of other surfaces.
+    Fixed(i32),
+    /// Reserve the size of the surface along the anchored edge.
+    ///
+    /// The surface has to be anchored to one edge, to one edge and both edges perpendicular to
+    /// it, or to a corner, where the edge the surface is longer along is reserved. Corners need
+    /// a compositor supporting layer-shell v5.
+    Auto,
+}
+
+/// How the surfaces are shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShellMode {
+    /// As wlr-layer-shell surfaces, e.g. bars, docks and overlays.
+    LayerShell,
+    /// As regular `xdg_toplevel` windows, for compositors without wlr-layer-shell such as
+    /// GNOME's Mutter.
+    ///
+    /// The size from the [`LayerShellOptions`] is only the initial window size, zero picks a
+    /// default one. Layer surface options, like the anchor and the exclusive zone, and the
+    /// [`LayerSurfaceHandle`] have no effect.
+    Toplevel,
+}
 
 #[derive(Default)]
 pub struct LayerShellOptions {
@@ -43,7 +145,146 @@ pub struct LayerShellOptions {
     pub width: u32,
     pub height: u32,
     pub anchor: Option<Anchor>,
+    /// Output to show the surface on, the compositor chooses one if this is `None`.
+    pub output: Option<OutputSelector>,
+    pub output_fallback: OutputFallback,
+    /// Create a surface on every output, including ones connected later, instead of one
+    /// surface on [`LayerShellOptions::output`], which is ignored then.
+    ///
+    /// Every surface is drawn as its own egui viewport, [`Frame::output`](crate::Frame::output)
+    /// tells which output the app is drawing for.
+    pub per_output: bool,
+    /// Forces the surfaces to be shown with this shell, `None` uses wlr-layer-shell if the
+    /// compositor supports it and falls back to [`ShellMode::Toplevel`] otherwise.
+    pub shell_mode: Option<ShellMode>,
+    pub margin: Margin,
+    pub exclusive_zone: Option<ExclusiveZone>,
     pub keyboard_interactivity: Option<KeyboardInteractivity>,
+    /// Fits the size of the surfaces to their content, starting from `width` and `height`.
+    ///
+    /// Resizing takes a roundtrip to the compositor, the previous frame stays shown until then.
+    /// Not supported for [`ShellMode::Toplevel`] windows.
+    pub auto_size: Option<AutoSize>,
+    /// Number of samples per pixel used for anti-aliasing, `0` or `1` disables msaa.
+    ///
+    /// Falls back to the highest supported count below this if the adapter does not support it,
+    /// for the surface or the depth format.
+    pub msaa_samples: u32,
+    /// Format of a depth buffer attached to the egui render pass, so custom paint callbacks
+    /// can draw depth tested content. No depth buffer is allocated if this is `None`.
+    ///
+    /// Creating the surface fails if the format has no depth aspect or can't be rendered to.
+    pub depth_format: Option<egui_wgpu::wgpu::TextureFormat>,
+    /// Backends wgpu may pick an adapter from, can be overridden with `WGPU_BACKEND`.
+    pub backends: egui_wgpu::wgpu::Backends,
+    pub power_preference: egui_wgpu::wgpu::PowerPreference,
+    /// Only consider software adapters, e.g. for running on machines without a GPU.
+    pub force_fallback_adapter: bool,
+    /// Prefer the adapter whose name contains this, ignoring case.
+    ///
+    /// Can be overridden with `WGPU_ADAPTER_NAME`. Falls back to the default selection if no
+    /// compatible adapter matches.
+    pub adapter_name: Option<String>,
+    /// Use wgpu objects owned by the app instead of creating new ones.
+    ///
+    /// The adapter selection options above are ignored for [`WgpuSetup::Existing`].
+    pub wgpu_setup: WgpuSetup,
+    /// Highlight the regions that changed since the previous frame.
+    ///
+    /// Frames with the same shapes as the previous one are not presented at all. Otherwise the
+    /// whole frame is still rendered, the changed regions are only sent to the compositor as
+    /// damage, which saves compositing work but not GPU work in this process. Shapes are
+    /// compared by their position in the frame, so one added in front of the others damages
+    /// everything after it.
+    pub debug_damage: bool,
+    /// Call [`App::pre_render`] before egui is drawn, to render below the UI.
+    pub pre_render: bool,
+    /// Render egui into an intermediate texture and call [`App::post_render`] to draw it onto
+    /// the surface.
+    pub post_render: bool,
+    /// Shortcut toggling an overlay with the frame rate, frame times and repaint reason.
+    pub frame_stats_shortcut: Option<egui::KeyboardShortcut>,
+    /// Load fonts installed on the system before the first frame, instead of only egui's
+    /// bundled ones.
+    pub system_fonts: Option<SystemFonts>,
+}
+
+/// What frames are rendered with.
+// there is one of these per surface, so the size difference does not matter
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum RenderBackend {
+    Wgpu(WgpuState),
+    /// A wgpu backend whose device got lost, released until it is created again at this size.
+    Lost {
+        width: u32,
+        height: u32,
+    },
+    /// Rasterizes on the CPU, picked if wgpu finds no adapter.
+    #[cfg(feature = "shm")]
+    Shm(ShmState),
+}
+
+impl RenderBackend {
+    pub(crate) fn wgpu(&self) -> Option<&WgpuState> {
+        match self {
+            RenderBackend::Wgpu(wgpu_state) => Some(wgpu_state),
+            RenderBackend::Lost { .. } => None,
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(_) => None,
+        }
+    }
+
+    pub(crate) fn wgpu_mut(&mut self) -> Option<&mut WgpuState> {
+        match self {
+            RenderBackend::Wgpu(wgpu_state) => Some(wgpu_state),
+            RenderBackend::Lost { .. } => None,
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(_) => None,
+        }
+    }
+
+    fn is_device_lost(&self) -> bool {
+        match self {
+            RenderBackend::Wgpu(wgpu_state) => wgpu_state.is_device_lost(),
+            RenderBackend::Lost { .. } => true,
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(_) => false,
+        }
+    }
+
+    /// Drops the wgpu state if its device got lost, keeping only its size.
+    fn release_lost_device(&mut self) {
+        if let RenderBackend::Wgpu(wgpu_state) = self {
+            if wgpu_state.is_device_lost() {
+                let configuration = &wgpu_state.surface_configuration;
+                *self = RenderBackend::Lost {
+                    width: configuration.width,
+                    height: configuration.height,
+                };
+            }
+        }
+    }
+
+    fn supports_screenshots(&self) -> bool {
+        match self {
+            RenderBackend::Wgpu(_) => true,
+            RenderBackend::Lost { .. } => false,
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(_) => true,
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        match self {
+            RenderBackend::Wgpu(wgpu_state) => wgpu_state.resize(width, height),
+            RenderBackend::Lost {
+                width: lost_width,
+                height: lost_height,
+            } => (*lost_width, *lost_height) = (width, height),
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(shm_state) => shm_state.resize(width, height),
+        }
+    }
 }
 
 pub(crate) struct WgpuLayerShellState {
@@ -53,178 +294,1325 @@ pub(crate) struct WgpuLayerShellState {
     seat_state: SeatState,
     output_state: OutputState,
     pub(crate) queue_handle: Arc<QueueHandle<Self>>,
+    connection: Connection,
+    compositor_state: CompositorState,
+    /// `None` if the compositor does not support wlr-layer-shell.
+    layer_shell: Option<LayerShell>,
+    shell_mode: ShellMode,
 
-    pub(crate) layer: LayerSurface,
     pointer: Option<WlPointer>,
     keyboard: Option<WlKeyboard>,
-
-    pub(crate) has_frame_callback: bool,
-    is_configured: bool,
+    /// Surface with keyboard focus, key events go to its viewport.
+    keyboard_focus: Option<wl_surface::WlSurface>,
+    /// Seat and serial of the last button press, popups opened by it grab the input.
+    last_button_press: Option<(wl_seat::WlSeat, u32)>,
 
     pub(crate) exit: bool,
 
-    pub(crate) wgpu_state: WgpuState,
+    pub(crate) surfaces: Vec<Surface>,
+    /// Egui popups that don't fit on their layer surface, `None` without xdg_wm_base.
+    xdg_shell: Option<XdgShell>,
+    popups: Vec<PopupSurface>,
+    surface_options: SurfaceOptions,
+    per_output: bool,
+    /// Device every wgpu surface draws with, `None` until the first one got created.
+    wgpu_device: Option<SharedDevice>,
+    /// When to try recreating a lost device again, and the delay used for that attempt, set if
+    /// the last attempt failed, e.g. while the GPU is not back after a resume.
+    device_recovery_retry: Option<(Instant, Duration)>,
+    wgpu_options: WgpuOptions,
+    #[cfg(feature = "shm")]
+    shm: Option<Shm>,
+    /// Set once wgpu found no adapter, all surfaces are rasterized on the CPU then.
+    #[cfg(feature = "shm")]
+    software_renderer: Option<SoftwareRenderer>,
     pub(crate) egui_state: egui_state::State,
-    pub(crate) draw_request: Arc<RwLock<Option<Instant>>>,
+    /// When egui wants each viewport to be drawn next.
+    pub(crate) draw_requests: Arc<RwLock<HashMap<ViewportId, Instant>>>,
+    immediate_viewports: Rc<RefCell<ImmediateViewports>>,
+    debug_damage: bool,
+    pre_render: bool,
+    post_render: bool,
+    presentation: Option<PresentationState>,
+    frame_stats_shortcut: Option<egui::KeyboardShortcut>,
+    layer_command_sender: channel::Sender<(ViewportId, LayerSurfaceCommand)>,
 }
 
 impl WgpuLayerShellState {
-    pub(crate) fn new(loop_handle: LoopHandle<'static, Self>, options: LayerShellOptions) -> Self {
+    pub(crate) fn new(
+        loop_handle: LoopHandle<'static, Self>,
+        options: LayerShellOptions,
+    ) -> Result<Self> {
         let connection = Connection::connect_to_env().unwrap();
-        let (global_list, event_queue) = registry_queue_init(&connection).unwrap();
+        let (global_list, mut event_queue) = registry_queue_init(&connection).unwrap();
         let queue_handle: Arc<QueueHandle<WgpuLayerShellState>> = Arc::new(event_queue.handle());
 
-        WaylandSource::new(connection.clone(), event_queue)
-            .insert(loop_handle.clone())
-            .unwrap();
-
         let compositor_state = CompositorState::bind(&global_list, &queue_handle)
             .expect("wl_compositor not available");
-
-        let wl_surface = compositor_state.create_surface(&queue_handle);
-
-        let layer_shell =
-            LayerShell::bind(&global_list, &queue_handle).expect("layer shell not available");
-        let layer_surface = layer_shell.create_layer_surface(
-            &queue_handle,
-            wl_surface,
-            options.layer.unwrap_or(Layer::Top),
-            Some(options.namespace),
-            None,
-        );
-        if let Some(anchor) = options.anchor {
-            layer_surface.set_anchor(anchor);
+        let layer_shell = LayerShell::bind(&global_list, &queue_handle)
+            .inspect_err(|error| log::info!("wlr-layer-shell not available: {error}"))
+            .ok();
+        let xdg_shell = XdgShell::bind(&global_list, &queue_handle)
+            .inspect_err(|error| log::info!("xdg_wm_base not available: {error}"))
+            .ok();
+        let shell_mode = match (options.shell_mode, &layer_shell) {
+            (Some(shell_mode), _) => shell_mode,
+            (None, Some(_)) => ShellMode::LayerShell,
+            (None, None) => {
+                log::warn!("wlr-layer-shell not available, opening a toplevel window instead");
+                ShellMode::Toplevel
+            }
+        };
+        let is_available = match shell_mode {
+            ShellMode::LayerShell => layer_shell.is_some(),
+            ShellMode::Toplevel => xdg_shell.is_some(),
+        };
+        if !is_available {
+            return Err(Error::ShellUnavailable(shell_mode));
         }
-        if let Some(keyboard_interactivity) = options.keyboard_interactivity {
-            layer_surface.set_keyboard_interactivity(keyboard_interactivity);
+        let per_output = options.per_output && shell_mode == ShellMode::LayerShell;
+        if options.per_output && !per_output {
+            log::warn!("Toplevel windows can't be shown per output, opening one window");
         }
-        layer_surface.set_size(options.width, options.height);
-        layer_surface.commit();
-
-        let wgpu_state = WgpuState::new(&connection.backend(), layer_surface.wl_surface())
-            .expect("Could not create wgpu state");
+        // only used if wgpu finds no adapter
+        #[cfg(feature = "shm")]
+        let shm = Shm::bind(&global_list, &queue_handle)
+            .inspect_err(|error| log::info!("wl_shm not available: {error}"))
+            .ok();
 
         let egui_context = egui::Context::default();
+        if let Some(system_fonts) = &options.system_fonts {
+            system_fonts.install(&egui_context);
+        }
+        // child viewports get their own layer surfaces
+        egui_context.set_embed_viewports(false);
+        let immediate_viewports = Rc::default();
+        viewport::set_immediate_viewport_renderer(&immediate_viewports);
 
-        let draw_request = Arc::new(RwLock::new(None));
+        let draw_requests = Arc::new(RwLock::new(HashMap::new()));
 
         egui_context.set_request_repaint_callback({
-            let draw_request = Arc::clone(&draw_request);
+            let draw_requests = Arc::clone(&draw_requests);
             move |info| {
-                let mut draw_request = draw_request.write().unwrap();
-                *draw_request = Some(Instant::now() + info.delay);
+                let mut draw_requests = draw_requests.write().unwrap();
+                draw_requests.insert(info.viewport_id, Instant::now() + info.delay);
             }
         });
 
-        let egui_state = egui_state::State::new(
-            egui_context,
-            &wgpu_state.device,
-            wgpu_state.surface_configuration.format,
-            None,
-            1,
-        );
+        let presentation = PresentationState::bind(&global_list, &queue_handle);
+
+        let (sender, receiver) = channel::channel();
+        loop_handle
+            .insert_source(receiver, |event, _, state: &mut Self| {
+                if let channel::Event::Msg((viewport_id, command)) = event {
+                    let Some(surface) = state
+                        .surfaces
+                        .iter_mut()
+                        .find(|surface| surface.viewport_id == viewport_id)
+                    else {
+                        return;
+                    };
+                    surface.layer_commands.push(command);
+                    let mut draw_requests = state.draw_requests.write().unwrap();
+                    draw_requests.insert(viewport_id, Instant::now());
+                }
+            })
+            .expect("Could not insert layer surface channel");
 
-        WgpuLayerShellState {
+        let mut state = WgpuLayerShellState {
             loop_handle: loop_handle.clone(),
             registry_state: RegistryState::new(&global_list),
             seat_state: SeatState::new(&global_list, &queue_handle),
             output_state: OutputState::new(&global_list, &queue_handle),
 
             exit: false,
-            layer: layer_surface,
 
             pointer: None,
             keyboard: None,
-
-            has_frame_callback: false,
-            is_configured: false,
+            keyboard_focus: None,
+            last_button_press: None,
 
             queue_handle,
+            connection,
+            compositor_state,
+            layer_shell,
+            shell_mode,
+
+            surfaces: Vec::new(),
+            xdg_shell,
+            popups: Vec::new(),
+            surface_options: SurfaceOptions::new(&options),
+            per_output,
+            wgpu_device: None,
+            device_recovery_retry: None,
+            wgpu_options: WgpuOptions {
+                msaa_samples: options.msaa_samples,
+                depth_format: options.depth_format,
+                backends: options.backends,
+                power_preference: options.power_preference,
+                force_fallback_adapter: options.force_fallback_adapter,
+                adapter_name: options.adapter_name.clone(),
+                setup: options.wgpu_setup.clone(),
+                post_render: options.post_render,
+            },
+            #[cfg(feature = "shm")]
+            shm,
+            #[cfg(feature = "shm")]
+            software_renderer: None,
+            egui_state: egui_state::State::without_renderer(egui_context),
+            draw_requests,
+            immediate_viewports,
+            debug_damage: options.debug_damage,
+            pre_render: options.pre_render,
+            post_render: options.post_render,
+            presentation,
+            frame_stats_shortcut: options.frame_stats_shortcut,
+            layer_command_sender: sender,
+        };
+
+        // the outputs get announced, and their surfaces created, before the app is created
+        event_queue
+            .roundtrip(&mut state)
+            .expect("Could not query outputs");
+        if !state.per_output {
+            let output = match &options.output {
+                Some(selector) => state.select_output(selector, options.output_fallback)?,
+                None => None,
+            };
+            let options = state.surface_options.clone();
+            state.create_surface(output, ViewportId::ROOT, &options);
+        }
+
+        WaylandSource::new(state.connection.clone(), event_queue)
+            .insert(loop_handle)
+            .unwrap();
+
+        Ok(state)
+    }
+
+    /// Creates a layer surface on the output, drawn as the given viewport, returns its index.
+    fn create_surface(
+        &mut self,
+        output: Option<wl_output::WlOutput>,
+        viewport_id: ViewportId,
+        options: &SurfaceOptions,
+    ) -> usize {
+        let wl_surface = self.compositor_state.create_surface(&self.queue_handle);
+        let shell = match (&self.layer_shell, &self.xdg_shell, self.shell_mode) {
+            (Some(layer_shell), _, ShellMode::LayerShell) => {
+                let layer = layer_shell.create_layer_surface(
+                    &self.queue_handle,
+                    wl_surface,
+                    options.layer,
+                    Some(options.namespace.clone()),
+                    output.as_ref(),
+                );
+                options.apply(&layer);
+                layer.commit();
+                ShellSurface::Layer(layer)
+            }
+            (_, Some(xdg_shell), ShellMode::Toplevel) => {
+                let window = xdg_shell.create_window(
+                    wl_surface,
+                    WindowDecorations::ServerDefault,
+                    &self.queue_handle,
+                );
+                window.set_title(options.namespace.clone());
+                window.set_app_id(options.namespace.clone());
+                window.commit();
+                ShellSurface::Toplevel(window)
+            }
+            _ => unreachable!("the shell got checked when binding the globals"),
+        };
 
-            egui_state,
-            wgpu_state,
-            draw_request,
+        let backend = self.create_backend(shell.wl_surface());
+        let mut surface = Surface::new(backend, shell, viewport_id, output, options);
+        surface.frame.wgpu_render_state = render_state(&surface.backend, &self.egui_state);
+        surface.frame.shell_mode = Some(self.shell_mode);
+        match self.shell_mode {
+            ShellMode::LayerShell => {
+                surface.frame.layer_surface = Some(LayerSurfaceHandle::new(
+                    self.layer_command_sender.clone(),
+                    viewport_id,
+                ));
+            }
+            ShellMode::Toplevel => {
+                surface.auto_size = None;
+                // until the compositor suggests another size
+                let size = [options.width, options.height];
+                surface.surface_size = [0, 1].map(|axis| match size[axis] {
+                    0 => DEFAULT_WINDOW_SIZE[axis],
+                    length => length,
+                });
+            }
         }
+        surface.frame.output = surface
+            .output
+            .as_ref()
+            .and_then(|output| self.output_state.info(output));
+        self.surfaces.push(surface);
+        self.surfaces.len() - 1
     }
 
-    //fn request_redraw(&self, )
+    /// Creates what the surface is drawn with, sharing the device with the other surfaces.
+    fn create_backend(&mut self, wl_surface: &wl_surface::WlSurface) -> RenderBackend {
+        #[cfg(feature = "shm")]
+        if self.software_renderer.is_some() {
+            return self.shm_backend();
+        }
 
-    pub(crate) fn should_draw(&mut self) -> bool {
-        if !self.has_frame_callback {
-            return false;
+        if let Some(shared) = &self.wgpu_device {
+            let wgpu_state = WgpuState::for_surface(shared, &self.connection.backend(), wl_surface)
+                .unwrap_or_else(|error| panic!("Could not create wgpu state: {error}"));
+            return RenderBackend::Wgpu(wgpu_state);
         }
 
-        if !self.egui_state.input().events.is_empty() {
-            return true;
+        match WgpuState::new(
+            &self.connection.backend(),
+            wl_surface,
+            self.wgpu_options.clone(),
+        ) {
+            Ok(wgpu_state) => {
+                self.egui_state.create_renderer(
+                    &wgpu_state.device,
+                    &wgpu_state.queue,
+                    wgpu_state.surface_configuration.format,
+                    wgpu_state.depth_format(),
+                    wgpu_state.msaa_samples,
+                );
+                self.wgpu_device = Some(wgpu_state.shared());
+                RenderBackend::Wgpu(wgpu_state)
+            }
+            #[cfg(feature = "shm")]
+            Err(WgpuStateError::NoAdapterError) => {
+                log::warn!("No wgpu adapter found, rendering on the CPU into shm buffers");
+                self.software_renderer = Some(SoftwareRenderer::default());
+                self.shm_backend()
+            }
+            Err(error) => panic!("Could not create wgpu state: {error}"),
         }
+    }
 
-        match *self.draw_request.read().unwrap() {
-            Some(time) => time <= Instant::now(),
-            None => false,
+    #[cfg(feature = "shm")]
+    fn shm_backend(&self) -> RenderBackend {
+        let shm = self.shm.as_ref().expect("wl_shm not available");
+        RenderBackend::Shm(ShmState::new(shm).expect("Could not create shm pool"))
+    }
+
+    fn remove_surface(&mut self, index: usize) {
+        let surface = self.surfaces.remove(index);
+        self.draw_requests
+            .write()
+            .unwrap()
+            .remove(&surface.viewport_id);
+        if self.keyboard_focus.as_ref() == Some(surface.wl_surface()) {
+            self.keyboard_focus = None;
+        }
+        self.close_popups(surface.viewport_id);
+
+        // child viewports can't be shown without their parent
+        while let Some(child) = self.surfaces.iter().position(|child| {
+            child.class != ViewportClass::Root && child.parent == surface.viewport_id
+        }) {
+            self.remove_surface(child);
         }
     }
 
+    pub(crate) fn surface_mut(
+        &mut self,
+        wl_surface: &wl_surface::WlSurface,
+    ) -> Option<&mut Surface> {
+        self.surfaces
+            .iter_mut()
+            .find(|surface| surface.wl_surface() == wl_surface)
+    }
+
+    /// Input of the viewport shown on the surface, with the offset from the surface's to the
+    /// viewport's coordinates, which only popups have.
+    pub(crate) fn input_mut(
+        &mut self,
+        wl_surface: &wl_surface::WlSurface,
+    ) -> Option<(&mut egui::RawInput, egui::Vec2)> {
+        if let Some(popup) = self
+            .popups
+            .iter()
+            .find(|popup| popup.wl_surface() == wl_surface)
+        {
+            let (parent, offset) = (popup.parent, popup.rect.min.to_vec2());
+            let surface = self
+                .surfaces
+                .iter_mut()
+                .find(|surface| surface.viewport_id == parent)?;
+            return Some((&mut surface.input, offset));
+        }
+
+        self.surface_mut(wl_surface)
+            .map(|surface| (&mut surface.input, egui::Vec2::ZERO))
+    }
+
+    /// Input of the surface with keyboard focus.
+    fn focused_input(&mut self) -> Option<&mut egui::RawInput> {
+        let focus = self.keyboard_focus.clone()?;
+        self.input_mut(&focus).map(|(input, _)| input)
+    }
+
+    fn is_device_lost(&self) -> bool {
+        self.surfaces
+            .iter()
+            .any(|surface| surface.backend.is_device_lost())
+    }
+
+    /// Repaints requested outside of a frame, e.g. from another thread, are for the root
+    /// viewport, which is not drawn with one surface per output, so every surface draws instead.
+    fn distribute_root_request(&self) {
+        if !self.per_output {
+            return;
+        }
+
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        if let Some(time) = draw_requests.remove(&ViewportId::ROOT) {
+            for surface in &self.surfaces {
+                let request = draw_requests.entry(surface.viewport_id).or_insert(time);
+                *request = (*request).min(time);
+            }
+        }
+    }
+
+    /// Viewports of the surfaces that should draw now.
+    fn surfaces_to_draw(&self) -> Vec<ViewportId> {
+        self.distribute_root_request();
+        let draw_requests = self.draw_requests.read().unwrap();
+        let mut viewport_ids = Vec::new();
+        for surface in &self.surfaces {
+            if !surface.should_draw(draw_requests.get(&surface.viewport_id)) {
+                continue;
+            }
+            let Some(index) = self.drawn_with(surface.viewport_id) else {
+                continue;
+            };
+            let drawn_with = &self.surfaces[index];
+            if (drawn_with.viewport_id == surface.viewport_id || drawn_with.can_draw())
+                && !viewport_ids.contains(&drawn_with.viewport_id)
+            {
+                viewport_ids.push(drawn_with.viewport_id);
+            }
+        }
+        viewport_ids
+    }
+
+    pub(crate) fn should_draw(&mut self) -> bool {
+        if self.is_device_lost() {
+            return self.can_recover_device();
+        }
+        !self.surfaces_to_draw().is_empty()
+    }
+
+    /// Whether recreating the lost device may be attempted now.
+    fn can_recover_device(&self) -> bool {
+        self.device_recovery_retry
+            .is_none_or(|(time, _)| time <= Instant::now())
+    }
+
     pub(crate) fn get_timeout(&self) -> Option<Duration> {
-        match *self.draw_request.read().unwrap() {
-            Some(instant) => {
-                if self.has_frame_callback {
-                    Some(instant.duration_since(Instant::now()))
-                } else {
-                    None
+        if self.is_device_lost() {
+            return Some(
+                self.device_recovery_retry
+                    .map_or(Duration::ZERO, |(time, _)| {
+                        time.saturating_duration_since(Instant::now())
+                    }),
+            );
+        }
+
+        self.distribute_root_request();
+        let draw_requests = self.draw_requests.read().unwrap();
+        let now = Instant::now();
+        let timeout = self
+            .surfaces
+            .iter()
+            .filter_map(|surface| {
+                let draw_request = draw_requests.get(&surface.viewport_id)?;
+                Some((*draw_request).max(surface.ready_at()?))
+            })
+            .min()
+            .map(|instant| instant.saturating_duration_since(now));
+
+        if self
+            .surfaces
+            .iter()
+            .all(|surface| surface.pending_screenshots.is_empty())
+        {
+            timeout
+        } else {
+            Some(timeout.map_or(SCREENSHOT_POLL_INTERVAL, |timeout| {
+                timeout.min(SCREENSHOT_POLL_INTERVAL)
+            }))
+        }
+    }
+
+    /// Hands finished screenshots to egui, without blocking on the ones still being read back.
+    pub(crate) fn poll_screenshots(&mut self) {
+        if self
+            .surfaces
+            .iter()
+            .all(|surface| surface.pending_screenshots.is_empty())
+        {
+            return;
+        }
+
+        // all surfaces share the device
+        if let Some(wgpu_state) = self
+            .surfaces
+            .iter()
+            .find_map(|surface| surface.backend.wgpu())
+        {
+            wgpu_state.device.poll(egui_wgpu::wgpu::Maintain::Poll);
+        }
+
+        for surface in &mut self.surfaces {
+            surface.poll_screenshots();
+        }
+    }
+
+    fn request_redraw(&mut self, index: usize) {
+        let surface = &mut self.surfaces[index];
+        surface.has_frame_callback = true;
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(surface.viewport_id, Instant::now());
+    }
+
+    pub(crate) fn creation_context(&self) -> CreationContext {
+        let first = self.surfaces.first();
+        CreationContext {
+            egui_ctx: self.egui_state.context().clone(),
+            wgpu_render_state: first.and_then(|surface| surface.frame.wgpu_render_state.clone()),
+            layer_surface: first
+                .filter(|_| !self.per_output)
+                .and_then(|surface| surface.frame.layer_surface.clone()),
+            shell_mode: Some(self.shell_mode),
+        }
+    }
+
+    /// Recreates all wgpu objects and the egui renderer after the device got lost,
+    /// then schedules a redraw so the surfaces come back on their own.
+    fn recover_from_device_loss(&mut self, application: &mut dyn App) {
+        log::warn!("recovering from wgpu device loss");
+
+        // drivers may refuse to create a surface for a wl_surface which still has one, so
+        // everything holding on to the lost device and its surfaces is dropped first, except
+        // for the egui renderer which the app may share and which is replaced in place
+        if self
+            .wgpu_device
+            .as_ref()
+            .is_some_and(SharedDevice::is_device_lost)
+        {
+            self.wgpu_device = None;
+        }
+        self.popups.clear();
+        for surface in &mut self.surfaces {
+            surface.backend.release_lost_device();
+            if surface.backend.wgpu().is_none() {
+                surface.frame.wgpu_render_state = None;
+            }
+        }
+
+        let backend = self.connection.backend();
+        let mut shared = self.wgpu_device.clone();
+        for surface in &mut self.surfaces {
+            let RenderBackend::Lost { width, height } = surface.backend else {
+                continue;
+            };
+            // the first surface gets a new device, the others share it
+            let result = match &shared {
+                Some(shared) => {
+                    WgpuState::for_surface(shared, &backend, surface.shell.wl_surface())
                 }
+                None => WgpuState::new(
+                    &backend,
+                    surface.shell.wl_surface(),
+                    self.wgpu_options.clone(),
+                ),
+            };
+            let mut wgpu_state = match result {
+                Ok(wgpu_state) => wgpu_state,
+                Err(error) => {
+                    // the surfaces created so far are kept, the others are attempted again
+                    let delay = self
+                        .device_recovery_retry
+                        .map_or(DEVICE_RECOVERY_RETRY_DELAY, |(_, delay)| {
+                            (delay * 2).min(MAX_DEVICE_RECOVERY_RETRY_DELAY)
+                        });
+                    log::error!("Could not recreate wgpu state, retrying in {delay:?}: {error}");
+                    self.device_recovery_retry = Some((Instant::now() + delay, delay));
+                    self.wgpu_device = shared;
+                    return;
+                }
+            };
+            wgpu_state.resize(width, height);
+            if shared.is_none() {
+                self.egui_state.create_renderer(
+                    &wgpu_state.device,
+                    &wgpu_state.queue,
+                    wgpu_state.surface_configuration.format,
+                    wgpu_state.depth_format(),
+                    wgpu_state.msaa_samples,
+                );
+                shared = Some(wgpu_state.shared());
             }
-            None => None,
+            surface.backend = RenderBackend::Wgpu(wgpu_state);
+        }
+        self.wgpu_device = shared;
+        self.device_recovery_retry = None;
+
+        for surface in &mut self.surfaces {
+            surface.frame.wgpu_render_state = render_state(&surface.backend, &self.egui_state);
+            surface.damage_tracker.invalidate();
+            // the buffers belonged to the lost device
+            surface.pending_screenshots.clear();
+        }
+        if let Some(render_state) = self
+            .surfaces
+            .first()
+            .and_then(|surface| surface.frame.wgpu_render_state.as_ref())
+        {
+            application.on_render_state_recreated(render_state);
+        }
+
+        for index in 0..self.surfaces.len() {
+            self.request_redraw(index);
         }
     }
 
     pub(crate) fn draw(&mut self, application: &mut dyn App) {
-        *self.draw_request.write().unwrap() = None;
-        self.has_frame_callback = false;
+        if self.is_device_lost() {
+            if self.can_recover_device() {
+                self.recover_from_device_loss(application);
+            }
+            return;
+        }
+
+        // drawing may create and remove the surfaces of child viewports
+        for viewport_id in self.surfaces_to_draw() {
+            if let Some(index) = self.surface_index(viewport_id) {
+                self.draw_surface(application, index);
+            }
+        }
+    }
 
-        let full_output = self
-            .egui_state
-            .process_events(|ctx| application.update(ctx));
+    fn draw_surface(&mut self, application: &mut dyn App, index: usize) {
+        let start = Instant::now();
+        let surface = &mut self.surfaces[index];
+        let viewport_id = surface.viewport_id;
+        self.draw_requests
+            .write()
+            .unwrap()
+            .remove(&surface.viewport_id);
+        surface.begin_frame();
 
-        let surface_texture = self
-            .wgpu_state
-            .surface
-            .get_current_texture()
-            .expect("Failed to acquire next swap chain texture");
+        if let Some(wgpu_state) = surface.backend.wgpu_mut() {
+            if let Some(gpu_timer) = &mut wgpu_state.gpu_timer {
+                wgpu_state.device.poll(egui_wgpu::wgpu::Maintain::Poll);
+                if let Some((frame_nr, gpu)) = gpu_timer.try_take() {
+                    surface.frame.stats.set_gpu_time(frame_nr, gpu);
+                }
+            }
+        }
+
+        if let Some(presentation) = &mut self.presentation {
+            // animations should advance to when the frame is shown, not when it is drawn
+            if let Some((time, refresh)) =
+                presentation.predict(&surface.presentation_timing, self.egui_state.elapsed())
+            {
+                surface.input.time = Some(time);
+                if let Some(refresh) = refresh {
+                    surface.input.predicted_dt = refresh.as_secs_f32();
+                }
+            }
+        }
+
+        let mut raw_input = surface.take_input();
+        // the extra layout passes must not handle the events again
+        let mut pass_input = surface.auto_size.map(|_| egui::RawInput {
+            events: Vec::new(),
+            dropped_files: Vec::new(),
+            ..raw_input.clone()
+        });
+        if let Some(pass_input) = &mut pass_input {
+            for info in pass_input.viewports.values_mut() {
+                info.events.clear();
+            }
+        }
+        self.lend_immediate_inputs(index);
+
+        let mut layout_size = self.surfaces[index].surface_size;
+        let mut passes = 0;
+        let (full_output, popups) = loop {
+            let surface = &mut self.surfaces[index];
+            let screen_rect = egui::Rect::from_min_size(
+                egui::Pos2::ZERO,
+                egui::vec2(layout_size[0] as f32, layout_size[1] as f32),
+            );
+            let popup_space = match self.xdg_shell {
+                Some(_) => surface.popup_space(layout_size),
+                None => screen_rect,
+            };
+            raw_input.screen_rect = Some(screen_rect);
+            let viewport_ui_cb = surface.viewport_ui_cb.clone();
+            let frame = &mut surface.frame;
+            let frame_stats_shortcut = self.frame_stats_shortcut;
+            let show_frame_stats = &mut surface.show_frame_stats;
+            let mut popups = Vec::new();
+            let mut used_size = egui::Vec2::ZERO;
+            let full_output = self.egui_state.run(raw_input, |ctx| {
+                // the panels are already laid out for the surface, only areas use the screen rect
+                ctx.input_mut(|input| input.screen_rect = popup_space);
+
+                // deferred viewports are drawn by their own callback, not the app
+                if let Some(viewport_ui_cb) = &viewport_ui_cb {
+                    viewport_ui_cb(ctx);
+                } else {
+                    if let Some(shortcut) = frame_stats_shortcut {
+                        if ctx.input_mut(|input| input.consume_shortcut(&shortcut)) {
+                            *show_frame_stats = !*show_frame_stats;
+                        }
+                    }
+
+                    application.update_with_frame(ctx, frame);
+
+                    if *show_frame_stats {
+                        stats::show_overlay(ctx, &frame.stats);
+                    }
+                }
+                used_size = ctx.used_size();
+
+                ctx.input_mut(|input| input.screen_rect = screen_rect);
+                if popup_space != screen_rect {
+                    popups = popup::take_popups(ctx, screen_rect);
+                }
+            });
+
+            let max_passes = surface
+                .auto_size
+                .map_or(0, |auto_size| auto_size.max_passes);
+            let fitted = surface
+                .auto_size
+                .map(|auto_size| auto_size.fit(used_size, layout_size, surface.requested_size));
+            match (&pass_input, fitted) {
+                (Some(pass_input), Some(fitted))
+                    if fitted != layout_size && passes < max_passes =>
+                {
+                    // laid out again at the size the content asked for, this pass is not shown
+                    self.discard_pass(&full_output);
+                    layout_size = fitted;
+                    raw_input = pass_input.clone();
+                    passes += 1;
+                }
+                _ => break (full_output, popups),
+            }
+        };
+
+        if self.request_auto_size(index, layout_size) {
+            // the previous frame stays shown until the surface got its new size
+            self.discard_pass(&full_output);
+            self.take_immediate_outputs();
+            return;
+        }
+        let immediate_outputs = self.take_immediate_outputs();
+        self.update_viewports(viewport_id, &full_output.viewport_output);
+
+        // the immediate viewports ran first, so their textures are uploaded first too
+        self.present_immediate_viewports(application, immediate_outputs);
+        match self.surface_index(viewport_id) {
+            Some(index) => {
+                self.present(application, index, full_output, start);
+                self.update_popups(viewport_id, popups);
+            }
+            None => self.discard_textures(&full_output.textures_delta),
+        }
+    }
+
+    /// Asks the compositor for the size the surface's content got laid out at, returns whether
+    /// the frame should wait for it instead of being shown.
+    fn request_auto_size(&mut self, index: usize, size: [u32; 2]) -> bool {
+        let surface = &mut self.surfaces[index];
+        if surface.auto_size.is_none() || size == surface.surface_size {
+            surface.skipped_for_resize = false;
+            return false;
+        }
+        if surface.awaiting_resize {
+            // drawn again once configured
+            surface.has_frame_callback = true;
+            return true;
+        }
+
+        let requested = [0, 1].map(|axis| match surface.requested_size[axis] {
+            0 => 0,
+            _ => size[axis],
+        });
+        if requested == surface.requested_size || surface.skipped_for_resize {
+            // the compositor gave it another size, or the content does not settle on one
+            surface.skipped_for_resize = false;
+            return false;
+        }
+
+        surface
+            .layer_commands
+            .push(LayerSurfaceCommand::Size(requested[0], requested[1]));
+        surface.apply_layer_commands();
+        surface.wl_surface().commit();
+        surface.awaiting_resize = true;
+        surface.skipped_for_resize = true;
+        // drawn again once configured
+        surface.has_frame_callback = true;
+        true
+    }
+
+    /// Renders and commits the frame of a surface, unless nothing changed.
+    fn present(
+        &mut self,
+        application: &mut dyn App,
+        index: usize,
+        mut full_output: FullOutput,
+        start: Instant,
+    ) {
+        let clear_color = application.clear_color(&self.egui_state.context().style().visuals);
+
+        if !full_output.textures_delta.is_empty() {
+            // the other surfaces may show the changed textures too, e.g. the font atlas
+            for (other, surface) in self.surfaces.iter_mut().enumerate() {
+                if other != index {
+                    surface.damage_tracker.invalidate();
+                }
+            }
+            for popup in &mut self.popups {
+                popup.damage_tracker.invalidate();
+            }
+        }
+        let surface = &mut self.surfaces[index];
+
+        // kept for the next frame while the device is lost
+        let take_screenshot =
+            surface.screenshot_requested && surface.backend.supports_screenshots();
+        if take_screenshot {
+            surface.screenshot_requested = false;
+        }
+
+        if self.pre_render || self.post_render {
+            // the custom passes may change anything on every frame
+            surface.damage_tracker.invalidate();
+        }
+        let damage = surface.damage_tracker.update(
+            &full_output.shapes,
+            &full_output.textures_delta,
+            clear_color,
+        );
+        // committed together with the frame's buffer
+        let layer_changed = surface.apply_layer_commands();
+
+        if damage == Damage::None && !take_screenshot {
+            if layer_changed {
+                surface.wl_surface().commit();
+            }
+            // nothing was committed, so no frame callback is pending either, the next frame
+            // waits for the refresh it would have been shown with instead
+            let refresh = surface
+                .presentation_timing
+                .refresh()
+                .unwrap_or(SKIPPED_FRAME_INTERVAL);
+            surface.skipped_frame_until = Some(Instant::now() + refresh);
+            return;
+        }
+
+        let screen_size = egui::vec2(
+            surface.surface_size[0] as f32,
+            surface.surface_size[1] as f32,
+        );
+        if self.debug_damage {
+            let screen_rect = egui::Rect::from_min_size(egui::Pos2::ZERO, screen_size);
+            full_output
+                .shapes
+                .extend(damage::debug_shapes(&damage, screen_rect));
+        }
+
+        let frame = FrameOutput {
+            shapes: full_output.shapes,
+            textures_delta: full_output.textures_delta,
+            pixels_per_point: full_output.pixels_per_point,
+            screen_size,
+            clear_color,
+            take_screenshot,
+            damage,
+        };
+        let is_committed = match &surface.backend {
+            RenderBackend::Wgpu(_) => self.draw_wgpu(application, index, frame),
+            // not drawn until the device is recreated
+            RenderBackend::Lost { .. } => false,
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(_) => self.draw_shm(index, frame),
+        };
+        if !is_committed {
+            return;
+        }
+
+        let surface = &mut self.surfaces[index];
+        let frame_nr = surface.frame.stats.push(FrameTiming {
+            total: start.elapsed().as_secs_f32(),
+            ..self.egui_state.timing
+        });
+        if let Some(gpu_timer) = surface
+            .backend
+            .wgpu_mut()
+            .and_then(|wgpu_state| wgpu_state.gpu_timer.as_mut())
+        {
+            gpu_timer.map(frame_nr);
+        }
+    }
+
+    /// Uploads and frees the textures of a frame that is not rendered, egui considers them
+    /// uploaded either way.
+    fn discard_textures(&mut self, textures_delta: &TexturesDelta) {
+        match &self.wgpu_device {
+            Some(shared) => {
+                self.egui_state
+                    .update_textures(&shared.device, &shared.queue, textures_delta)
+            }
+            None => self.egui_state.retain_textures(textures_delta),
+        }
+        #[cfg(feature = "shm")]
+        if let Some(software_renderer) = &mut self.software_renderer {
+            for (id, _) in &textures_delta.set {
+                if let Some(texture) = self.egui_state.texture(id) {
+                    software_renderer.set_texture(*id, texture);
+                }
+            }
+            for id in &textures_delta.free {
+                software_renderer.free_texture(id);
+            }
+        }
+        self.egui_state.free_textures(textures_delta);
+    }
+
+    /// Renders the frame with wgpu and presents it, returns whether a frame got committed.
+    fn draw_wgpu(&mut self, application: &mut dyn App, index: usize, frame: FrameOutput) -> bool {
+        let surface = &mut self.surfaces[index];
+        let Some(wgpu_state) = surface.backend.wgpu_mut() else {
+            unreachable!()
+        };
+
+        let surface_texture = match wgpu_state.surface.get_current_texture() {
+            Ok(surface_texture) => surface_texture,
+            Err(error) => {
+                // egui considers the textures uploaded, so this can't be skipped
+                self.egui_state.update_textures(
+                    &wgpu_state.device,
+                    &wgpu_state.queue,
+                    &frame.textures_delta,
+                );
+                self.egui_state.free_textures(&frame.textures_delta);
+                surface.damage_tracker.invalidate();
+
+                match error {
+                    egui_wgpu::wgpu::SurfaceError::Lost
+                    | egui_wgpu::wgpu::SurfaceError::Outdated => wgpu_state.reconfigure(),
+                    egui_wgpu::wgpu::SurfaceError::Timeout => {
+                        log::warn!("timed out acquiring the next swap chain texture")
+                    }
+                    error => panic!("Failed to acquire next swap chain texture: {error}"),
+                }
+                self.request_redraw(index);
+                return false;
+            }
+        };
 
         let surface_view = surface_texture
             .texture
             .create_view(&egui_wgpu::wgpu::TextureViewDescriptor::default());
 
-        let mut encoder = self
-            .wgpu_state
+        let mut encoder = wgpu_state
             .device
             .create_command_encoder(&egui_wgpu::wgpu::CommandEncoderDescriptor { label: None });
 
         let screen_descriptor = ScreenDescriptor {
             size_in_pixels: [
-                self.wgpu_state.surface_configuration.width,
-                self.wgpu_state.surface_configuration.height,
+                wgpu_state.surface_configuration.width,
+                wgpu_state.surface_configuration.height,
             ],
             pixels_per_point: 1.0, // todo: figure out where to get that from
         };
 
-        self.egui_state.draw(
-            &self.wgpu_state.device,
-            &self.wgpu_state.queue,
+        // egui renders into the intermediate texture if the app post-processes it
+        let egui_view = wgpu_state
+            .post_render_texture_view
+            .as_ref()
+            .unwrap_or(&surface_view);
+        let render_target = RenderTarget::new(
+            egui_view,
+            wgpu_state.msaa_texture_view.as_ref(),
+            wgpu_state.depth_texture_view.as_ref(),
+        );
+        let clear_color = wgpu_state.clear_color(frame.clear_color);
+
+        let load = if self.pre_render {
+            egui_state::clear(&mut encoder, render_target.view, clear_color);
+            application.pre_render(&mut RenderContext {
+                device: &wgpu_state.device,
+                queue: &wgpu_state.queue,
+                encoder: &mut encoder,
+                target: render_target.view,
+                target_format: wgpu_state.surface_configuration.format,
+                sample_count: wgpu_state.sample_count(),
+                size_in_pixels: screen_descriptor.size_in_pixels,
+                source: None,
+            });
+            egui_wgpu::wgpu::LoadOp::Load
+        } else {
+            egui_wgpu::wgpu::LoadOp::Clear(clear_color)
+        };
+
+        let size_in_pixels = screen_descriptor.size_in_pixels;
+        let primitives = self.egui_state.tessellate(
+            frame.shapes,
+            frame.pixels_per_point,
+            surface.buffer_transform,
+            frame.screen_size,
+        );
+        let mut user_cmd_bufs = self.egui_state.draw(
+            &wgpu_state.device,
+            &wgpu_state.queue,
             &mut encoder,
-            &surface_view,
+            render_target,
+            load,
             screen_descriptor,
-            full_output.shapes,
-            full_output.textures_delta,
+            &primitives,
+            frame.textures_delta,
+            wgpu_state
+                .gpu_timer
+                .as_mut()
+                .and_then(|gpu_timer| gpu_timer.timestamp_writes()),
         );
-        self.wgpu_state.queue.submit(Some(encoder.finish()));
+        if let Some(gpu_timer) = &wgpu_state.gpu_timer {
+            gpu_timer.resolve(&mut encoder);
+        }
 
-        self.layer
-            .wl_surface()
-            .frame(&self.queue_handle, self.layer.wl_surface().clone());
+        if self.post_render {
+            application.post_render(&mut RenderContext {
+                device: &wgpu_state.device,
+                queue: &wgpu_state.queue,
+                encoder: &mut encoder,
+                target: &surface_view,
+                target_format: wgpu_state.surface_configuration.format,
+                sample_count: 1,
+                size_in_pixels,
+                source: Some(egui_view),
+            });
+        }
+        let screenshot = frame.take_screenshot.then(|| {
+            if wgpu_state.can_copy_surface() {
+                return TextureReadback::new(
+                    &wgpu_state.device,
+                    &mut encoder,
+                    &surface_texture.texture,
+                );
+            }
 
+            // egui is drawn again into a texture which can be copied, without the custom passes
+            let texture = wgpu_state.create_screenshot_texture();
+            let view = texture.create_view(&egui_wgpu::wgpu::TextureViewDescriptor::default());
+            user_cmd_bufs.extend(self.egui_state.draw(
+                &wgpu_state.device,
+                &wgpu_state.queue,
+                &mut encoder,
+                RenderTarget::new(
+                    &view,
+                    wgpu_state.msaa_texture_view.as_ref(),
+                    wgpu_state.depth_texture_view.as_ref(),
+                ),
+                egui_wgpu::wgpu::LoadOp::Clear(clear_color),
+                ScreenDescriptor {
+                    size_in_pixels,
+                    pixels_per_point: 1.0,
+                },
+                &primitives,
+                egui::TexturesDelta::default(),
+                None,
+            ));
+            TextureReadback::new(&wgpu_state.device, &mut encoder, &texture)
+        });
+        let submit_start = Instant::now();
+        wgpu_state.queue.submit(
+            user_cmd_bufs
+                .into_iter()
+                .chain(std::iter::once(encoder.finish())),
+        );
+        self.egui_state.timing.render += submit_start.elapsed().as_secs_f32();
+        if let Some(screenshot) = screenshot {
+            surface
+                .pending_screenshots
+                .push((screenshot.map(), surface.buffer_transform));
+        }
+
+        self.prepare_commit(&self.surfaces[index], &frame.damage, frame.pixels_per_point);
         surface_texture.present();
+
+        true
     }
+
+    /// Rasterizes the frame on the CPU and attaches it, returns whether a frame got committed.
+    #[cfg(feature = "shm")]
+    fn draw_shm(&mut self, index: usize, frame: FrameOutput) -> bool {
+        let surface = &mut self.surfaces[index];
+        let (RenderBackend::Shm(shm_state), Some(software_renderer)) =
+            (&mut surface.backend, &mut self.software_renderer)
+        else {
+            unreachable!()
+        };
+
+        let start = Instant::now();
+        self.egui_state.retain_textures(&frame.textures_delta);
+        for (id, _) in &frame.textures_delta.set {
+            if let Some(texture) = self.egui_state.texture(id) {
+                software_renderer.set_texture(*id, texture);
+            }
+        }
+        self.egui_state.timing.upload = start.elapsed().as_secs_f32();
+
+        let primitives = self.egui_state.tessellate(
+            frame.shapes,
+            frame.pixels_per_point,
+            surface.buffer_transform,
+            frame.screen_size,
+        );
+
+        let size = [shm_state.width as usize, shm_state.height as usize];
+        let stride = shm_state.stride();
+        let start = Instant::now();
+        let clear_color = wgpu_state::clear_color(
+            egui_wgpu::wgpu::TextureFormat::Bgra8Unorm,
+            egui_wgpu::wgpu::CompositeAlphaMode::PreMultiplied,
+            frame.clear_color,
+        );
+        let [r, g, b, a] = [clear_color.r, clear_color.g, clear_color.b, clear_color.a]
+            .map(|channel| (channel * 255.0).round() as u8);
+        let clear_color = egui::Color32::from_rgba_premultiplied(r, g, b, a);
+        let is_committed = match shm_state.render(
+            software_renderer,
+            clear_color,
+            &primitives,
+            frame.pixels_per_point,
+        ) {
+            Some((buffer, canvas)) => {
+                self.egui_state.timing.render = start.elapsed().as_secs_f32();
+
+                if frame.take_screenshot {
+                    let image = transform::image_from_buffer(
+                        surface.buffer_transform,
+                        shm_screenshot(canvas, size, stride),
+                    );
+                    surface.input.events.push(egui::Event::Screenshot {
+                        viewport_id: surface.viewport_id,
+                        image: Arc::new(image),
+                    });
+                }
+
+                if let Err(error) = buffer.attach_to(surface.shell.wl_surface()) {
+                    log::error!("Failed to attach shm buffer: {error}");
+                }
+                true
+            }
+            None => false,
+        };
+
+        for id in &frame.textures_delta.free {
+            software_renderer.free_texture(id);
+        }
+        self.egui_state.free_textures(&frame.textures_delta);
+
+        if !is_committed {
+            // the compositor still reads from both buffers, one is released soon
+            surface.damage_tracker.invalidate();
+            surface.has_frame_callback = true;
+            let mut draw_requests = self.draw_requests.write().unwrap();
+            draw_requests.insert(surface.viewport_id, Instant::now() + SHM_BUFFER_RETRY_DELAY);
+            return false;
+        }
+
+        if frame.damage == Damage::Full {
+            // unlike wgpu, nothing damages the buffer on its own
+            surface.wl_surface().damage_buffer(0, 0, i32::MAX, i32::MAX);
+        }
+        let surface = &self.surfaces[index];
+        self.prepare_commit(surface, &frame.damage, frame.pixels_per_point);
+        surface.wl_surface().commit();
+
+        true
+    }
+
+    /// Resizes the surface to the size the compositor configured it with.
+    fn configure_surface(&mut self, index: usize, [width, height]: [u32; 2]) {
+        let surface = &mut self.surfaces[index];
+        if !surface.is_configured {
+            surface.is_configured = true;
+            surface.has_frame_callback = true;
+            // recorded by `transform_changed` while the surface had no size yet
+            if surface.buffer_transform != wl_output::Transform::Normal {
+                surface
+                    .wl_surface()
+                    .set_buffer_transform(surface.buffer_transform);
+            }
+        }
+        surface.awaiting_resize = false;
+        // e.g. after the size got changed through a `LayerSurfaceHandle`
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(surface.viewport_id, Instant::now());
+
+        surface.surface_size = [width, height];
+        surface.resize_buffers();
+        // committed with the next frame
+        surface.update_exclusive_zone();
+
+        surface.set_screen_size(width, height);
+    }
+
+    /// Damages the changed regions and requests a frame callback and presentation feedback,
+    /// right before the next buffer gets committed.
+    fn prepare_commit(&self, surface: &Surface, damage: &Damage, pixels_per_point: f32) {
+        let wl_surface = surface.wl_surface();
+        if let Damage::Regions(regions) = damage {
+            // the driver may still damage the whole buffer when presenting, this is just a hint
+            let screen_size = egui::vec2(
+                surface.surface_size[0] as f32,
+                surface.surface_size[1] as f32,
+            );
+            for region in regions {
+                let region =
+                    transform::rect_to_buffer(surface.buffer_transform, *region, screen_size)
+                        * pixels_per_point;
+                let region =
+                    egui::Rect::from_min_max(region.min.floor(), region.max.ceil()).expand(1.0);
+                wl_surface.damage_buffer(
+                    region.min.x as i32,
+                    region.min.y as i32,
+                    region.width() as i32,
+                    region.height() as i32,
+                );
+            }
+        }
+
+        wl_surface.frame(&self.queue_handle, wl_surface.clone());
+        if let Some(presentation) = &self.presentation {
+            presentation.request_feedback(wl_surface, &self.queue_handle);
+        }
+    }
+}
+
+/// The wgpu objects for the app, `None` if the surface is not drawn with wgpu.
+fn render_state(
+    backend: &RenderBackend,
+    egui_state: &egui_state::State,
+) -> Option<egui_wgpu::RenderState> {
+    let wgpu_state = backend.wgpu()?;
+    Some(wgpu_state.render_state(egui_state.renderer()?))
+}
+
+/// Sets the exclusive zone of a surface of the given size, and the edge it is reserved at.
+fn set_exclusive_zone(layer: &LayerSurface, zone: ExclusiveZone, anchor: Anchor, size: [u32; 2]) {
+    let edge = exclusive_edge(anchor, size);
+    // only needed for corners, but replaces the edge set for a previous anchor as well, which
+    // may not be anchored anymore
+    layer.set_exclusive_edge(edge);
+    let zone = match (zone, edge) {
+        (ExclusiveZone::Fixed(zone), _) => zone,
+        (ExclusiveZone::Auto, Some(Anchor::TOP | Anchor::BOTTOM)) => size[1] as i32,
+        (ExclusiveZone::Auto, Some(_)) => size[0] as i32,
+        (ExclusiveZone::Auto, None) => 0,
+    };
+    layer.set_exclusive_zone(zone);
+}
+
+/// The edge the exclusive zone is reserved at: the surface is anchored to it, and possibly to
+/// both edges perpendicular to it. In a corner, it is the edge the surface is longer along.
+fn exclusive_edge(anchor: Anchor, [width, height]: [u32; 2]) -> Option<Anchor> {
+    let horizontal = Anchor::LEFT | Anchor::RIGHT;
+    let vertical = Anchor::TOP | Anchor::BOTTOM;
+    let edge = [
+        (Anchor::TOP, horizontal),
+        (Anchor::BOTTOM, horizontal),
+        (Anchor::LEFT, vertical),
+        (Anchor::RIGHT, vertical),
+    ]
+    .into_iter()
+    .find(|&(edge, perpendicular)| anchor == edge || anchor == edge | perpendicular)
+    .map(|(edge, _)| edge);
+
+    edge.or_else(|| {
+        is_corner(anchor).then(|| {
+            if width >= height {
+                anchor & vertical
+            } else {
+                anchor & horizontal
+            }
+        })
+    })
+}
+
+fn is_corner(anchor: Anchor) -> bool {
+    [Anchor::TOP, Anchor::BOTTOM]
+        .into_iter()
+        .any(|vertical| anchor == vertical | Anchor::LEFT || anchor == vertical | Anchor::RIGHT)
+}
+
+/// Warns if the exclusive zone of a surface with the anchor reserves nothing.
+fn warn_if_exclusive_zone_ignored(
+    layer: &LayerSurface,
+    zone: Option<ExclusiveZone>,
+    anchor: Anchor,
+) {
+    let reserves = match zone {
+        Some(ExclusiveZone::Fixed(zone)) => zone > 0,
+        Some(ExclusiveZone::Auto) => true,
+        None => false,
+    };
+    if !reserves {
+        return;
+    }
+
+    if is_corner(anchor) {
+        if !layer.supports_exclusive_edge() {
+            log::warn!(
+                "The exclusive zone reserves nothing for a surface anchored to the corner \
+                 {anchor:?}, the compositor does not support layer-shell v5 to pick an edge"
+            );
+        }
+    } else if exclusive_edge(anchor, [0, 0]).is_none() {
+        log::warn!(
+            "The exclusive zone reserves nothing for a surface anchored to {anchor:?}, it needs \
+             to be anchored to one edge or a corner"
+        );
+    }
+}
+
+/// What a frame needs to be rendered, independent of the backend.
+struct FrameOutput {
+    shapes: Vec<ClippedShape>,
+    textures_delta: TexturesDelta,
+    pixels_per_point: f32,
+    /// Size of the viewport in points.
+    screen_size: egui::Vec2,
+    /// Unmultiplied RGBA in gamma space, as returned by [`App::clear_color`].
+    clear_color: [f32; 4],
+    take_screenshot: bool,
+    damage: Damage,
+}
+
+/// Copies the premultiplied ARGB8888 pixels of a shm buffer into an image.
+#[cfg(feature = "shm")]
+fn shm_screenshot(canvas: &[u8], [width, height]: [usize; 2], stride: usize) -> egui::ColorImage {
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in canvas.chunks_exact(stride).take(height) {
+        for pixel in row[..width * 4].chunks_exact(4) {
+            rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+    egui::ColorImage::from_rgba_premultiplied([width, height], &rgba)
 }
 
 delegate_registry!(WgpuLayerShellState);
@@ -245,24 +1633,50 @@ impl OutputHandler for WgpuLayerShellState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        if self.per_output {
+            let viewport_id = ViewportId::from_hash_of(output.id());
+            let options = self.surface_options.clone();
+            self.create_surface(Some(output), viewport_id, &options);
+        }
     }
 
     fn update_output(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        let Some(info) = self.output_state.info(&output) else {
+            return;
+        };
+        for surface in &mut self.surfaces {
+            if let Some(output) = &mut surface.frame.output {
+                if output.id == info.id {
+                    *output = info.clone();
+                }
+            }
+        }
     }
 
     fn output_destroyed(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        if !self.per_output {
+            return;
+        }
+
+        if let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.output.as_ref() == Some(&output))
+        {
+            self.remove_surface(index);
+        }
     }
 }
 
@@ -281,28 +1695,56 @@ impl CompositorHandler for WgpuLayerShellState {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
-        _new_transform: wl_output::Transform,
+        wl_surface: &wl_surface::WlSurface,
+        new_transform: wl_output::Transform,
     ) {
+        let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.wl_surface() == wl_surface)
+        else {
+            return;
+        };
+        let surface = &mut self.surfaces[index];
+        if new_transform == surface.buffer_transform {
+            return;
+        }
+
+        surface.buffer_transform = new_transform;
+        // the buffers get their size with the first configure, which applies the transform
+        if !surface.is_configured {
+            return;
+        }
+
+        // applied with the next commit, together with the first pre-transformed buffer
+        wl_surface.set_buffer_transform(new_transform);
+        surface.resize_buffers();
+        self.request_redraw(index);
     }
 
     fn frame(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _time: u32,
     ) {
-        self.has_frame_callback = true;
+        if let Some(surface) = self.surface_mut(surface) {
+            surface.has_frame_callback = true;
+        }
     }
 
     fn surface_enter(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
-        _output: &wl_output::WlOutput,
+        surface: &wl_surface::WlSurface,
+        output: &wl_output::WlOutput,
     ) {
+        let info = self.output_state.info(output);
+        if let Some(surface) = self.surface_mut(surface) {
+            surface.frame.output = info;
+        }
     }
 
     fn surface_leave(
@@ -315,31 +1757,101 @@ impl CompositorHandler for WgpuLayerShellState {
     }
 }
 
-delegate_layer!(WgpuLayerShellState);
+delegate_dispatch!(WgpuLayerShellState: [ZwlrLayerShellV1: GlobalData] => LayerShell);
+delegate_dispatch!(WgpuLayerShellState: [ZwlrLayerSurfaceV1: LayerSurfaceData] => LayerShell);
 impl LayerShellHandler for WgpuLayerShellState {
-    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _layer: &LayerSurface) {
-        self.exit = true;
+    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
+        let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.shell.layer() == Some(layer))
+        else {
+            return;
+        };
+        if !self.per_output && self.surfaces[index].class == ViewportClass::Root {
+            self.exit = true;
+            return;
+        }
+
+        // e.g. when its output is about to be removed
+        self.remove_surface(index);
     }
 
     fn configure(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _layer: &LayerSurface,
+        layer: &LayerSurface,
         configure: LayerSurfaceConfigure,
         _serial: u32,
     ) {
-        if !self.is_configured {
-            self.is_configured = true;
-            self.has_frame_callback = true;
-            *self.draw_request.write().unwrap() = Some(Instant::now());
+        if let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.shell.layer() == Some(layer))
+        {
+            self.configure_surface(index, [configure.new_size.0, configure.new_size.1]);
         }
+    }
+}
 
-        self.wgpu_state
-            .resize(configure.new_size.0, configure.new_size.1);
+delegate_xdg_shell!(WgpuLayerShellState);
+delegate_xdg_window!(WgpuLayerShellState);
+impl WindowHandler for WgpuLayerShellState {
+    fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, window: &Window) {
+        let Some(surface) = self
+            .surfaces
+            .iter_mut()
+            .find(|surface| surface.shell.toplevel() == Some(window))
+        else {
+            return;
+        };
+        if surface.class == ViewportClass::Root {
+            self.exit = true;
+            return;
+        }
+
+        // it is up to the app to stop showing child viewports
+        let viewport_id = surface.viewport_id;
+        let info = surface.input.viewports.entry(viewport_id).or_default();
+        info.events.push(egui::ViewportEvent::Close);
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(viewport_id, Instant::now());
+    }
+
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        window: &Window,
+        configure: WindowConfigure,
+        _serial: u32,
+    ) {
+        let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.shell.toplevel() == Some(window))
+        else {
+            return;
+        };
+        // the window keeps its size if the compositor leaves it up to us
+        let [width, height] = self.surfaces[index].surface_size;
+        let size = [
+            configure.new_size.0.map_or(width, NonZeroU32::get),
+            configure.new_size.1.map_or(height, NonZeroU32::get),
+        ];
+        self.configure_surface(index, size);
+    }
+}
 
-        self.egui_state
-            .set_size(configure.new_size.0, configure.new_size.1);
+#[cfg(feature = "shm")]
+delegate_shm!(WgpuLayerShellState);
+#[cfg(feature = "shm")]
+impl ShmHandler for WgpuLayerShellState {
+    fn shm_state(&mut self) -> &mut Shm {
+        self.shm
+            .as_mut()
+            .expect("wl_shm only sends events once it got bound")
     }
 }
 
@@ -375,7 +1887,9 @@ impl SeatHandler for WgpuLayerShellState {
                             None,
                             self.loop_handle.clone(),
                             Box::new(|state, _wl_kbd, event| {
-                                handle_key_press(event, true, &mut state.egui_state.input());
+                                if let Some(input) = state.focused_input() {
+                                    handle_key_press(event, true, input);
+                                }
                             }),
                         )
                         .expect("Failed to create keyboard"),
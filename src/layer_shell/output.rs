@@ -0,0 +1,81 @@
+use smithay_client_toolkit::output::OutputInfo;
+use wayland_client::protocol::wl_output;
+
+use super::WgpuLayerShellState;
+use crate::{Error, Result};
+
+/// Which output the layer surface is shown on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputSelector {
+    /// Connector name, e.g. `DP-1`, which needs `wl_output` version 4 or `xdg_output`.
+    Name(String),
+    /// Part of the make, model or description, ignoring case.
+    Description(String),
+    /// Position in the order the compositor announced the outputs.
+    Index(usize),
+}
+
+/// What happens if no output matches the [`OutputSelector`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFallback {
+    /// Let the compositor choose, like without a selector.
+    #[default]
+    Compositor,
+    /// Use the first output.
+    First,
+    /// Fail with [`Error::NoMatchingOutput`](crate::Error::NoMatchingOutput).
+    Fail,
+}
+
+impl OutputSelector {
+    fn matches(&self, index: usize, info: &OutputInfo) -> bool {
+        match self {
+            OutputSelector::Name(name) => info.name.as_ref() == Some(name),
+            OutputSelector::Description(text) => {
+                let text = text.to_lowercase();
+                [
+                    Some(&info.make),
+                    Some(&info.model),
+                    info.description.as_ref(),
+                ]
+                .into_iter()
+                .flatten()
+                .any(|value| value.to_lowercase().contains(&text))
+            }
+            OutputSelector::Index(selected) => index == *selected,
+        }
+    }
+}
+
+impl WgpuLayerShellState {
+    /// Looks up the output to create the layer surface on, `None` lets the compositor choose.
+    ///
+    /// Outputs only announce their names after a roundtrip.
+    pub(crate) fn select_output(
+        &self,
+        selector: &OutputSelector,
+        fallback: OutputFallback,
+    ) -> Result<Option<wl_output::WlOutput>> {
+        let outputs: Vec<_> = self.output_state.outputs().collect();
+        let selected = outputs.iter().enumerate().find(|(index, output)| {
+            self.output_state
+                .info(output)
+                .is_some_and(|info| selector.matches(*index, &info))
+        });
+        if let Some((_, output)) = selected {
+            return Ok(Some(output.clone()));
+        }
+
+        match fallback {
+            OutputFallback::Compositor => {
+                log::warn!("No output matches {selector:?}, letting the compositor choose");
+                Ok(None)
+            }
+            OutputFallback::First => {
+                log::warn!("No output matches {selector:?}, using the first one");
+                Ok(outputs.into_iter().next())
+            }
+            OutputFallback::Fail => Err(Error::NoMatchingOutput(selector.clone())),
+        }
+    }
+}
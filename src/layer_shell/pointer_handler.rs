@@ -1,11 +1,11 @@
 use egui::{PointerButton, Vec2};
 use smithay_client_toolkit::{
     delegate_pointer,
-    seat::pointer::{PointerEvent, PointerEventKind, PointerHandler},
+    seat::pointer::{PointerData, PointerEvent, PointerEventKind, PointerHandler},
 };
 use wayland_client::{
     protocol::wl_pointer::{self},
-    Connection, QueueHandle,
+    Connection, Proxy, QueueHandle,
 };
 
 use super::WgpuLayerShellState;
@@ -17,11 +17,20 @@ impl PointerHandler for WgpuLayerShellState {
         &mut self,
         _: &Connection,
         _: &QueueHandle<Self>,
-        _: &wl_pointer::WlPointer,
+        pointer: &wl_pointer::WlPointer,
         events: &[PointerEvent],
     ) {
         for event in events {
-            let position = egui::pos2(event.position.0 as f32, event.position.1 as f32);
+            if let PointerEventKind::Press { serial, .. } = event.kind {
+                if let Some(pointer_data) = pointer.data::<PointerData>() {
+                    self.last_button_press = Some((pointer_data.seat().clone(), serial));
+                }
+            }
+
+            let Some((input, offset)) = self.input_mut(&event.surface) else {
+                continue;
+            };
+            let position = egui::pos2(event.position.0 as f32, event.position.1 as f32) + offset;
             let egui_event = match event.kind {
                 PointerEventKind::Enter { .. } | PointerEventKind::Motion { .. } => {
                     egui::Event::PointerMoved(position)
@@ -32,7 +41,7 @@ impl PointerHandler for WgpuLayerShellState {
                     if let Some(button) = translate_button(button) {
                         egui::Event::PointerButton {
                             button,
-                            modifiers: self.egui_state.modifiers(),
+                            modifiers: input.modifiers,
                             pos: position,
                             pressed: matches!(event.kind, PointerEventKind::Press { .. }),
                         }
@@ -47,10 +56,10 @@ impl PointerHandler for WgpuLayerShellState {
                 } => egui::Event::MouseWheel {
                     unit: egui::MouseWheelUnit::Point,
                     delta: Vec2::new(-horizontal.absolute as f32, -vertical.absolute as f32),
-                    modifiers: self.egui_state.modifiers(),
+                    modifiers: input.modifiers,
                 },
             };
-            self.egui_state.push_event(egui_event);
+            input.events.push(egui_event);
         }
     }
 }
@@ -0,0 +1,426 @@
+use std::time::Instant;
+
+use egui::{epaint::ClippedShape, LayerId, Order, ViewportId};
+use egui_wgpu::ScreenDescriptor;
+use smithay_client_toolkit::{
+    compositor::Region,
+    delegate_xdg_popup,
+    reexports::protocols::xdg::shell::client::xdg_positioner::{
+        Anchor, ConstraintAdjustment, Gravity,
+    },
+    shell::xdg::{
+        popup::{Popup, PopupConfigure, PopupHandler},
+        XdgPositioner, XdgSurface,
+    },
+};
+use wayland_client::{
+    protocol::{wl_output::Transform, wl_surface::WlSurface},
+    Connection, Proxy, QueueHandle,
+};
+
+use super::{RenderBackend, WgpuLayerShellState};
+use crate::{
+    damage::{Damage, DamageTracker},
+    egui_state::RenderTarget,
+};
+
+/// An egui popup, menu or tooltip that does not fit on its layer surface, cut out of the frame to
+/// be shown as an `xdg_popup`.
+pub(crate) struct PopupShapes {
+    layer_id: LayerId,
+    /// The popup surface, including the shadow, in the coordinates of the parent viewport.
+    rect: egui::Rect,
+    /// The area of the popup, without the shadow.
+    content: egui::Rect,
+    shapes: Vec<ClippedShape>,
+    /// Popups opened by a click grab the input, so the compositor dismisses them on clicks
+    /// elsewhere; tooltips don't.
+    grab: bool,
+}
+
+/// Takes the shapes of the foreground and tooltip layers reaching outside of the surface.
+///
+/// Has to be called at the end of the frame, before egui paints the layers.
+pub(crate) fn take_popups(ctx: &egui::Context, screen_rect: egui::Rect) -> Vec<PopupShapes> {
+    let layer_ids: Vec<LayerId> = ctx.memory(|memory| {
+        memory
+            .layer_ids()
+            .filter(|layer_id| matches!(layer_id.order, Order::Foreground | Order::Tooltip))
+            .collect()
+    });
+    let shadow = ctx.style().visuals.popup_shadow.margin();
+
+    layer_ids
+        .into_iter()
+        .filter_map(|layer_id| {
+            let content = ctx.memory(|memory| memory.area_rect(layer_id.id))?;
+            if screen_rect.contains_rect(content) {
+                return None;
+            }
+            // empty if the area was not shown this frame
+            let shapes =
+                ctx.graphics_mut(|graphics| graphics.get_mut(layer_id).map(std::mem::take))?;
+            if shapes.is_empty() {
+                return None;
+            }
+
+            Some(PopupShapes {
+                layer_id,
+                rect: {
+                    let rect = content + shadow;
+                    egui::Rect::from_min_max(rect.min.floor(), rect.max.ceil())
+                },
+                content,
+                shapes: shapes.all_entries().cloned().collect(),
+                grab: layer_id.order == Order::Foreground,
+            })
+        })
+        .collect()
+}
+
+/// An `xdg_popup` showing one egui layer of a layer surface's frame.
+pub(crate) struct PopupSurface {
+    // declared first so the wgpu surface is dropped before the wl_surface it draws to
+    backend: RenderBackend,
+    popup: Popup,
+    layer_id: LayerId,
+    /// The viewport whose frames the popup is drawn with.
+    pub(crate) parent: ViewportId,
+    /// Where the popup surface is in the coordinates of the parent viewport.
+    pub(crate) rect: egui::Rect,
+    is_configured: bool,
+    pub(crate) damage_tracker: DamageTracker,
+}
+
+impl PopupSurface {
+    pub(crate) fn wl_surface(&self) -> &WlSurface {
+        self.popup.wl_surface()
+    }
+}
+
+impl WgpuLayerShellState {
+    /// Shows the popups cut out of a frame of the viewport, and closes the ones no longer shown.
+    pub(crate) fn update_popups(&mut self, viewport_id: ViewportId, popups: Vec<PopupShapes>) {
+        // moved or resized popups are opened again where xdg_popup.reposition is unavailable
+        let can_reposition = self
+            .xdg_shell
+            .as_ref()
+            .is_some_and(|xdg_shell| xdg_shell.xdg_wm_base().version() >= 3);
+        self.popups.retain(|popup| {
+            popup.parent != viewport_id
+                || popups.iter().any(|shown| {
+                    shown.layer_id == popup.layer_id && (can_reposition || shown.rect == popup.rect)
+                })
+        });
+
+        for shown in popups {
+            let index =
+                match self.popups.iter().position(|popup| {
+                    popup.parent == viewport_id && popup.layer_id == shown.layer_id
+                }) {
+                    Some(index) => index,
+                    None => {
+                        self.open_popup(viewport_id, &shown);
+                        // drawn once configured
+                        continue;
+                    }
+                };
+            if self.popups[index].rect != shown.rect {
+                self.reposition_popup(index, &shown);
+                // drawn once configured at the new position
+                continue;
+            }
+            self.draw_popup(index, shown);
+        }
+    }
+
+    pub(crate) fn close_popups(&mut self, viewport_id: ViewportId) {
+        self.popups.retain(|popup| popup.parent != viewport_id);
+    }
+
+    /// Places the popup at the area, relative to its parent viewport.
+    fn create_positioner(
+        &self,
+        viewport_id: ViewportId,
+        shown: &PopupShapes,
+    ) -> Option<XdgPositioner> {
+        let xdg_shell = self.xdg_shell.as_ref()?;
+        let parent = self
+            .surfaces
+            .iter()
+            .find(|surface| surface.viewport_id == viewport_id)?;
+
+        let positioner = match XdgPositioner::new(xdg_shell) {
+            Ok(positioner) => positioner,
+            Err(error) => {
+                log::error!("Could not create xdg_positioner: {error}");
+                return None;
+            }
+        };
+        let size = shown.content.size().ceil().max(egui::Vec2::splat(1.0));
+        positioner.set_size(size.x as i32, size.y as i32);
+        // the anchor has to be on the parent, the popup is moved to the area with the offset
+        let [width, height] = parent.surface_size.map(|length| length.max(1) as f32 - 1.0);
+        let min = shown.content.min.round();
+        let anchor = min.clamp(egui::Pos2::ZERO, egui::pos2(width, height));
+        positioner.set_anchor_rect(anchor.x as i32, anchor.y as i32, 1, 1);
+        positioner.set_offset((min.x - anchor.x) as i32, (min.y - anchor.y) as i32);
+        positioner.set_anchor(Anchor::TopLeft);
+        positioner.set_gravity(Gravity::BottomRight);
+        positioner
+            .set_constraint_adjustment(ConstraintAdjustment::SlideX | ConstraintAdjustment::SlideY);
+        Some(positioner)
+    }
+
+    /// Sets the window geometry and input region of the popup surface to the area.
+    fn set_popup_geometry(&self, popup: &Popup, shown: &PopupShapes) {
+        let size = shown.content.size().ceil().max(egui::Vec2::splat(1.0));
+        // the shadow is drawn around the window geometry, and does not take input
+        let geometry = shown.content.translate(-shown.rect.min.to_vec2());
+        popup.xdg_surface().set_window_geometry(
+            geometry.min.x as i32,
+            geometry.min.y as i32,
+            size.x as i32,
+            size.y as i32,
+        );
+        if let Ok(region) = Region::new(&self.compositor_state) {
+            region.add(
+                geometry.min.x as i32,
+                geometry.min.y as i32,
+                size.x as i32,
+                size.y as i32,
+            );
+            popup
+                .wl_surface()
+                .set_input_region(Some(region.wl_region()));
+        }
+    }
+
+    fn open_popup(&mut self, viewport_id: ViewportId, shown: &PopupShapes) {
+        let Some(positioner) = self.create_positioner(viewport_id, shown) else {
+            return;
+        };
+        let Some(xdg_shell) = &self.xdg_shell else {
+            return;
+        };
+        let Some(parent) = self
+            .surfaces
+            .iter()
+            .find(|surface| surface.viewport_id == viewport_id)
+        else {
+            return;
+        };
+
+        let wl_surface = self.compositor_state.create_surface(&self.queue_handle);
+        // layer surfaces are made the parent below instead
+        let parent_xdg_surface = parent.shell.toplevel().map(|window| window.xdg_surface());
+        let popup = match Popup::from_surface(
+            parent_xdg_surface,
+            &positioner,
+            &self.queue_handle,
+            wl_surface,
+            xdg_shell,
+        ) {
+            Ok(popup) => popup,
+            Err(error) => {
+                log::error!("Could not create xdg_popup: {error}");
+                return;
+            }
+        };
+        self.set_popup_geometry(&popup, shown);
+        if let Some(layer) = parent.shell.layer() {
+            layer.get_popup(popup.xdg_popup());
+        }
+        if shown.grab {
+            if let Some((seat, serial)) = &self.last_button_press {
+                popup.xdg_popup().grab(seat, *serial);
+            }
+        }
+        popup.wl_surface().commit();
+
+        let surface_size = shown.rect.size().ceil();
+        let mut backend = self.create_backend(popup.wl_surface());
+        backend.resize(surface_size.x as u32, surface_size.y as u32);
+        self.popups.push(PopupSurface {
+            backend,
+            popup,
+            layer_id: shown.layer_id,
+            parent: viewport_id,
+            rect: shown.rect,
+            is_configured: false,
+            damage_tracker: DamageTracker::default(),
+        });
+    }
+
+    /// Moves and resizes the popup to the area with `xdg_popup.reposition`.
+    fn reposition_popup(&mut self, index: usize, shown: &PopupShapes) {
+        let Some(positioner) = self.create_positioner(self.popups[index].parent, shown) else {
+            return;
+        };
+        let popup = &self.popups[index];
+        self.set_popup_geometry(&popup.popup, shown);
+        // the token is not checked, the geometry is committed with the first frame drawn after
+        // the configure at the latest position
+        popup.popup.reposition(&positioner, 0);
+
+        let popup = &mut self.popups[index];
+        let surface_size = shown.rect.size().ceil();
+        popup
+            .backend
+            .resize(surface_size.x as u32, surface_size.y as u32);
+        popup.rect = shown.rect;
+        popup.is_configured = false;
+    }
+
+    /// Renders the popup's layer, unless it did not change.
+    fn draw_popup(&mut self, index: usize, shown: PopupShapes) {
+        let popup = &mut self.popups[index];
+        if !popup.is_configured {
+            return;
+        }
+
+        let offset = -popup.rect.min.to_vec2();
+        let mut shapes = shown.shapes;
+        for clipped in &mut shapes {
+            clipped.shape.translate(offset);
+            clipped.clip_rect = clipped.clip_rect.translate(offset);
+        }
+        if popup
+            .damage_tracker
+            .update(&shapes, &egui::TexturesDelta::default(), [0.0; 4])
+            == Damage::None
+        {
+            return;
+        }
+
+        let screen_size = popup.rect.size().ceil();
+        // the parent's frame uploaded the textures already
+        let primitives = self
+            .egui_state
+            .tessellate(shapes, 1.0, Transform::Normal, screen_size);
+
+        match &mut popup.backend {
+            RenderBackend::Wgpu(wgpu_state) => {
+                let surface_texture = match wgpu_state.surface.get_current_texture() {
+                    Ok(surface_texture) => surface_texture,
+                    Err(error) => {
+                        log::warn!("Could not draw popup: {error}");
+                        wgpu_state.reconfigure();
+                        popup.damage_tracker.invalidate();
+                        return;
+                    }
+                };
+                let surface_view = surface_texture
+                    .texture
+                    .create_view(&egui_wgpu::wgpu::TextureViewDescriptor::default());
+                let mut encoder = wgpu_state.device.create_command_encoder(
+                    &egui_wgpu::wgpu::CommandEncoderDescriptor { label: None },
+                );
+                let render_target = RenderTarget::new(
+                    &surface_view,
+                    wgpu_state.msaa_texture_view.as_ref(),
+                    wgpu_state.depth_texture_view.as_ref(),
+                );
+                let user_cmd_bufs = self.egui_state.draw(
+                    &wgpu_state.device,
+                    &wgpu_state.queue,
+                    &mut encoder,
+                    render_target,
+                    egui_wgpu::wgpu::LoadOp::Clear(wgpu_state.clear_color([0.0; 4])),
+                    ScreenDescriptor {
+                        size_in_pixels: [
+                            wgpu_state.surface_configuration.width,
+                            wgpu_state.surface_configuration.height,
+                        ],
+                        pixels_per_point: 1.0,
+                    },
+                    &primitives,
+                    egui::TexturesDelta::default(),
+                    None,
+                );
+                wgpu_state.queue.submit(
+                    user_cmd_bufs
+                        .into_iter()
+                        .chain(std::iter::once(encoder.finish())),
+                );
+                surface_texture.present();
+            }
+            // popups are closed when the device is lost
+            RenderBackend::Lost { .. } => {}
+            #[cfg(feature = "shm")]
+            RenderBackend::Shm(shm_state) => {
+                let Some(software_renderer) = &mut self.software_renderer else {
+                    unreachable!()
+                };
+                match shm_state.render(
+                    software_renderer,
+                    egui::Color32::TRANSPARENT,
+                    &primitives,
+                    1.0,
+                ) {
+                    Some((buffer, _)) => {
+                        if let Err(error) = buffer.attach_to(popup.popup.wl_surface()) {
+                            log::error!("Failed to attach shm buffer: {error}");
+                        }
+                        let wl_surface = popup.popup.wl_surface();
+                        wl_surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+                        wl_surface.commit();
+                    }
+                    // both buffers are still read by the compositor
+                    None => popup.damage_tracker.invalidate(),
+                }
+            }
+        }
+    }
+
+    /// Closes the egui popup, menu or tooltip shown in the `xdg_popup`.
+    fn dismiss_popup(&mut self, index: usize) {
+        let popup = self.popups.remove(index);
+        self.egui_state
+            .context()
+            .memory_mut(|memory| memory.close_popup());
+        if popup.layer_id.order == Order::Foreground {
+            // the compositor ended the grab, which every open menu shares
+            self.egui_state
+                .context()
+                .data_mut(|data| data.remove_by_type::<egui::menu::BarState>());
+        }
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(popup.parent, Instant::now());
+    }
+}
+
+delegate_xdg_popup!(WgpuLayerShellState);
+
+impl PopupHandler for WgpuLayerShellState {
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        popup: &Popup,
+        _config: PopupConfigure,
+    ) {
+        let Some(popup) = self
+            .popups
+            .iter_mut()
+            .find(|surface| surface.popup == *popup)
+        else {
+            return;
+        };
+        popup.is_configured = true;
+        popup.damage_tracker.invalidate();
+        // the popup is drawn with the next frame of its parent
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(popup.parent, Instant::now());
+    }
+
+    fn done(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, popup: &Popup) {
+        if let Some(index) = self
+            .popups
+            .iter()
+            .position(|surface| surface.popup == *popup)
+        {
+            self.dismiss_popup(index);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use wayland_client::{
+    globals::GlobalList, protocol::wl_surface::WlSurface, Connection, Dispatch, QueueHandle,
+};
+use wayland_protocols::wp::presentation_time::client::{
+    wp_presentation::{self, WpPresentation},
+    wp_presentation_feedback::{self, WpPresentationFeedback},
+};
+
+use super::WgpuLayerShellState;
+
+/// Tracks when frames actually got shown, to predict when the next one will be.
+pub(crate) struct PresentationState {
+    presentation: WpPresentation,
+    /// Clock the compositor reports timestamps in, announced right after binding.
+    clock_id: Option<u32>,
+    /// Time of the presentation clock at which egui's time started.
+    origin: Option<Duration>,
+}
+
+/// When the last frame of a surface got shown, surfaces on different outputs refresh
+/// independently.
+#[derive(Default)]
+pub(crate) struct PresentationTiming {
+    last_presented: Option<Duration>,
+    /// Duration of a refresh cycle, `None` if the output has no fixed refresh rate.
+    refresh: Option<Duration>,
+}
+
+impl PresentationTiming {
+    /// Duration of a refresh cycle of the output the surface was last shown on.
+    pub(crate) fn refresh(&self) -> Option<Duration> {
+        self.refresh
+    }
+}
+
+impl PresentationState {
+    /// Binds `wp_presentation`, returns `None` if the compositor does not support it.
+    pub(crate) fn bind(
+        global_list: &GlobalList,
+        queue_handle: &QueueHandle<WgpuLayerShellState>,
+    ) -> Option<Self> {
+        let presentation = global_list
+            .bind::<WpPresentation, _, _>(queue_handle, 1..=1, ())
+            .inspect_err(|error| log::info!("presentation time feedback not available: {error}"))
+            .ok()?;
+
+        Some(Self {
+            presentation,
+            clock_id: None,
+            origin: None,
+        })
+    }
+
+    /// Asks for feedback on the next commit of the surface.
+    pub(crate) fn request_feedback(
+        &self,
+        surface: &WlSurface,
+        queue_handle: &QueueHandle<WgpuLayerShellState>,
+    ) {
+        self.presentation
+            .feedback(surface, queue_handle, surface.clone());
+    }
+
+    /// When the frame a surface draws now will be presented, in seconds on egui's timeline
+    /// which started `elapsed` ago, and the refresh interval if the output has a fixed one.
+    pub(crate) fn predict(
+        &mut self,
+        timing: &PresentationTiming,
+        elapsed: Duration,
+    ) -> Option<(f64, Option<Duration>)> {
+        let now = clock_now(self.clock_id?)?;
+        let origin = *self.origin.get_or_insert(now.saturating_sub(elapsed));
+
+        let next_presentation = match (timing.last_presented, timing.refresh) {
+            (Some(last_presented), Some(refresh)) => {
+                // the frame can't be shown before the first refresh after now
+                let refreshes = now.saturating_sub(last_presented).as_nanos() / refresh.as_nanos();
+                last_presented + refresh * (refreshes as u32 + 1)
+            }
+            _ => now,
+        };
+
+        Some((
+            next_presentation.saturating_sub(origin).as_secs_f64(),
+            timing.refresh,
+        ))
+    }
+}
+
+fn clock_now(clock_id: u32) -> Option<Duration> {
+    let mut timespec = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let result = unsafe { libc::clock_gettime(clock_id as libc::clockid_t, &mut timespec) };
+    (result == 0).then(|| Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32))
+}
+
+impl Dispatch<WpPresentation, ()> for WgpuLayerShellState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpPresentation,
+        event: wp_presentation::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let (wp_presentation::Event::ClockId { clk_id }, Some(presentation)) =
+            (event, &mut state.presentation)
+        {
+            presentation.clock_id = Some(clk_id);
+        }
+    }
+}
+
+impl Dispatch<WpPresentationFeedback, WlSurface> for WgpuLayerShellState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpPresentationFeedback,
+        event: wp_presentation_feedback::Event,
+        surface: &WlSurface,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // the surface may have been destroyed since
+        let Some(surface) = state.surface_mut(surface) else {
+            return;
+        };
+        let timing = &mut surface.presentation_timing;
+
+        if let wp_presentation_feedback::Event::Presented {
+            tv_sec_hi,
+            tv_sec_lo,
+            tv_nsec,
+            refresh,
+            ..
+        } = event
+        {
+            let seconds = (u64::from(tv_sec_hi) << 32) | u64::from(tv_sec_lo);
+            timing.last_presented = Some(Duration::new(seconds, tv_nsec));
+            timing.refresh = (refresh > 0).then(|| Duration::from_nanos(refresh.into()));
+        }
+    }
+}
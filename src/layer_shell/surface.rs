@@ -0,0 +1,395 @@
+use std::{sync::Arc, time::Instant};
+
+use egui::{DeferredViewportUiCallback, ViewportBuilder, ViewportClass, ViewportId};
+use smithay_client_toolkit::shell::{
+    wlr_layer::{Anchor, KeyboardInteractivity, Layer},
+    xdg::window::Window,
+    WaylandSurface,
+};
+use wayland_client::protocol::{wl_output, wl_surface::WlSurface};
+
+use super::{
+    handle::LayerSurfaceCommand, presentation::PresentationTiming, set_exclusive_zone,
+    warn_if_exclusive_zone_ignored, wlr_layer::LayerSurface, AutoSize, ExclusiveZone,
+    LayerShellOptions, Margin, RenderBackend,
+};
+use crate::{damage::DamageTracker, readback::PendingReadback, transform, Frame};
+
+/// How new layer surfaces are set up, taken from the [`LayerShellOptions`].
+#[derive(Clone)]
+pub(crate) struct SurfaceOptions {
+    pub(crate) layer: Layer,
+    pub(crate) namespace: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) anchor: Option<Anchor>,
+    pub(crate) margin: Margin,
+    pub(crate) exclusive_zone: Option<ExclusiveZone>,
+    pub(crate) keyboard_interactivity: Option<KeyboardInteractivity>,
+    pub(crate) auto_size: Option<AutoSize>,
+}
+
+impl SurfaceOptions {
+    pub(crate) fn new(options: &LayerShellOptions) -> Self {
+        let mut size = [options.width, options.height];
+        if let Some(auto_size) = &options.auto_size {
+            let anchor = options.anchor.unwrap_or(Anchor::empty());
+            let stretched = [
+                anchor.contains(Anchor::LEFT | Anchor::RIGHT),
+                anchor.contains(Anchor::TOP | Anchor::BOTTOM),
+            ];
+            size = auto_size.initial_size(size, stretched);
+        }
+
+        Self {
+            layer: options.layer.unwrap_or(Layer::Top),
+            namespace: options.namespace.clone(),
+            width: size[0],
+            height: size[1],
+            anchor: options.anchor,
+            margin: options.margin,
+            exclusive_zone: options.exclusive_zone,
+            keyboard_interactivity: options.keyboard_interactivity,
+            auto_size: options.auto_size,
+        }
+    }
+
+    /// Sets up a new layer surface, before its first commit.
+    pub(crate) fn apply(&self, layer: &LayerSurface) {
+        if let Some(anchor) = self.anchor {
+            layer.set_anchor(anchor);
+        }
+        if let Some(keyboard_interactivity) = self.keyboard_interactivity {
+            layer.set_keyboard_interactivity(keyboard_interactivity);
+        }
+        layer.set_size(self.width, self.height);
+        let Margin {
+            top,
+            right,
+            bottom,
+            left,
+        } = self.margin;
+        layer.set_margin(top, right, bottom, left);
+        let anchor = self.anchor.unwrap_or(Anchor::empty());
+        warn_if_exclusive_zone_ignored(layer, self.exclusive_zone, anchor);
+        if let Some(zone) = self.exclusive_zone {
+            set_exclusive_zone(layer, zone, anchor, [self.width, self.height]);
+        }
+    }
+}
+
+/// The role of a surface, depending on the [`ShellMode`](super::ShellMode).
+pub(crate) enum ShellSurface {
+    Layer(LayerSurface),
+    Toplevel(Window),
+}
+
+impl ShellSurface {
+    pub(crate) fn layer(&self) -> Option<&LayerSurface> {
+        match self {
+            ShellSurface::Layer(layer) => Some(layer),
+            ShellSurface::Toplevel(_) => None,
+        }
+    }
+
+    pub(crate) fn toplevel(&self) -> Option<&Window> {
+        match self {
+            ShellSurface::Layer(_) => None,
+            ShellSurface::Toplevel(window) => Some(window),
+        }
+    }
+
+    pub(crate) fn wl_surface(&self) -> &WlSurface {
+        match self {
+            ShellSurface::Layer(layer) => layer.wl_surface(),
+            ShellSurface::Toplevel(window) => window.wl_surface(),
+        }
+    }
+}
+
+/// A layer surface, or toplevel window, showing one egui viewport.
+pub(crate) struct Surface {
+    // declared first so the wgpu surface is dropped before the wl_surface it draws to
+    pub(crate) backend: RenderBackend,
+    pub(crate) shell: ShellSurface,
+    pub(crate) viewport_id: ViewportId,
+    /// `Root` for the surfaces created from the options, `Deferred` or `Immediate` for child
+    /// viewports shown by the app.
+    pub(crate) class: ViewportClass,
+    /// The viewport showing this one, if it is a child viewport.
+    pub(crate) parent: ViewportId,
+    /// Draws a deferred viewport, instead of the app's `update`.
+    pub(crate) viewport_ui_cb: Option<Arc<DeferredViewportUiCallback>>,
+    /// The builder a child viewport's layer surface was placed with.
+    pub(crate) builder: Option<ViewportBuilder>,
+    /// The output the surface got created for, `None` lets the compositor choose.
+    pub(crate) output: Option<wl_output::WlOutput>,
+    /// Input since the last frame of the viewport.
+    pub(crate) input: egui::RawInput,
+
+    pub(crate) has_frame_callback: bool,
+    /// Stands in for the frame callback after a frame without changes got skipped, so drawing
+    /// keeps its pace without committing.
+    pub(crate) skipped_frame_until: Option<Instant>,
+    pub(crate) is_configured: bool,
+
+    pub(crate) damage_tracker: DamageTracker,
+    /// Screenshots being read back, with the buffer transform they were rendered with.
+    pub(crate) pending_screenshots: Vec<(PendingReadback, wl_output::Transform)>,
+    /// Set by `ViewportCommand::Screenshot`, taken with the next frame.
+    pub(crate) screenshot_requested: bool,
+    pub(crate) frame: Frame,
+    pub(crate) presentation_timing: PresentationTiming,
+    pub(crate) show_frame_stats: bool,
+    /// Size of the surface as configured by the compositor.
+    pub(crate) surface_size: [u32; 2],
+    /// The compositor's preferred buffer transform, egui is drawn pre-transformed with it.
+    pub(crate) buffer_transform: wl_output::Transform,
+    /// Kept to recompute [`ExclusiveZone::Auto`] when the surface changes.
+    pub(crate) anchor: Anchor,
+    pub(crate) margin: Margin,
+    pub(crate) exclusive_zone: Option<ExclusiveZone>,
+    /// Fits the surface to its content, only for layer surfaces created from the options.
+    pub(crate) auto_size: Option<AutoSize>,
+    /// The size last set on the layer surface, `0` where the compositor stretches it.
+    pub(crate) requested_size: [u32; 2],
+    /// Whether a new size got requested and the compositor has not configured the surface since.
+    pub(crate) awaiting_resize: bool,
+    /// Whether the last frame got skipped for a new size, the next one is shown in any case.
+    pub(crate) skipped_for_resize: bool,
+    /// Changes queued by [`LayerSurfaceHandle`](super::LayerSurfaceHandle)s, applied with the
+    /// next frame.
+    pub(crate) layer_commands: Vec<LayerSurfaceCommand>,
+}
+
+impl Surface {
+    pub(crate) fn new(
+        backend: RenderBackend,
+        shell: ShellSurface,
+        viewport_id: ViewportId,
+        output: Option<wl_output::WlOutput>,
+        options: &SurfaceOptions,
+    ) -> Self {
+        Self {
+            backend,
+            shell,
+            viewport_id,
+            class: ViewportClass::Root,
+            parent: ViewportId::ROOT,
+            viewport_ui_cb: None,
+            builder: None,
+            output,
+            input: egui::RawInput {
+                // until the keyboard enters it
+                focused: false,
+                viewport_id,
+                ..Default::default()
+            },
+            has_frame_callback: false,
+            skipped_frame_until: None,
+            is_configured: false,
+            damage_tracker: DamageTracker::default(),
+            pending_screenshots: Vec::new(),
+            screenshot_requested: false,
+            frame: Frame::new(None),
+            presentation_timing: PresentationTiming::default(),
+            show_frame_stats: false,
+            surface_size: [options.width, options.height],
+            buffer_transform: wl_output::Transform::Normal,
+            anchor: options.anchor.unwrap_or(Anchor::empty()),
+            margin: options.margin,
+            exclusive_zone: options.exclusive_zone,
+            auto_size: options.auto_size,
+            requested_size: [options.width, options.height],
+            awaiting_resize: false,
+            skipped_for_resize: false,
+            layer_commands: Vec::new(),
+        }
+    }
+
+    pub(crate) fn wl_surface(&self) -> &WlSurface {
+        self.shell.wl_surface()
+    }
+
+    /// Takes the input for the next frame of the viewport.
+    pub(crate) fn take_input(&mut self) -> egui::RawInput {
+        take_input(&mut self.input)
+    }
+
+    /// When the surface can draw its next frame, `None` while waiting for a frame callback.
+    pub(crate) fn ready_at(&self) -> Option<Instant> {
+        if self.has_frame_callback {
+            return Some(Instant::now());
+        }
+        self.skipped_frame_until
+    }
+
+    /// Whether the surface may draw now.
+    pub(crate) fn can_draw(&self) -> bool {
+        self.ready_at().is_some_and(|time| time <= Instant::now())
+    }
+
+    /// Starts drawing a frame, which is waited for until its frame callback.
+    pub(crate) fn begin_frame(&mut self) {
+        self.has_frame_callback = false;
+        self.skipped_frame_until = None;
+    }
+
+    /// Whether the surface can and wants to draw, given the time egui requested a repaint of
+    /// its viewport at.
+    pub(crate) fn should_draw(&self, draw_request: Option<&Instant>) -> bool {
+        if !self.can_draw() {
+            return false;
+        }
+
+        !self.input.events.is_empty() || draw_request.is_some_and(|time| *time <= Instant::now())
+    }
+
+    /// Sets the size egui lays out the viewport for.
+    pub(crate) fn set_screen_size(&mut self, width: u32, height: u32) {
+        self.input.screen_rect = Some(egui::Rect::from_min_size(
+            egui::Pos2::ZERO,
+            egui::vec2(width as f32, height as f32),
+        ));
+    }
+
+    /// The output around the surface of the size, in the surface's coordinates, which popups
+    /// can be placed in. Just the surface if the output's size is not known.
+    pub(crate) fn popup_space(&self, [width, height]: [u32; 2]) -> egui::Rect {
+        let size = egui::vec2(width as f32, height as f32);
+        // where a toplevel is on the output is not known
+        let output_size = match self.shell {
+            ShellSurface::Layer(_) => self
+                .frame
+                .output
+                .as_ref()
+                .and_then(|output| output.logical_size),
+            ShellSurface::Toplevel(_) => None,
+        };
+        let Some((output_width, output_height)) = output_size else {
+            return egui::Rect::from_min_size(egui::Pos2::ZERO, size);
+        };
+
+        let output_size = egui::vec2(output_width as f32, output_height as f32);
+        let x = offset_on_output(
+            [
+                self.anchor.contains(Anchor::LEFT),
+                self.anchor.contains(Anchor::RIGHT),
+            ],
+            [self.margin.left, self.margin.right],
+            size.x,
+            output_size.x,
+        );
+        let y = offset_on_output(
+            [
+                self.anchor.contains(Anchor::TOP),
+                self.anchor.contains(Anchor::BOTTOM),
+            ],
+            [self.margin.top, self.margin.bottom],
+            size.y,
+            output_size.y,
+        );
+        egui::Rect::from_min_size(egui::pos2(-x, -y), output_size)
+    }
+
+    /// Hands finished screenshots to egui, without blocking on the ones still being read back.
+    pub(crate) fn poll_screenshots(&mut self) {
+        let viewport_id = self.viewport_id;
+        let input = &mut self.input;
+        self.pending_screenshots
+            .retain(|(screenshot, transform)| match screenshot.try_take() {
+                None => true,
+                Some(Ok(image)) => {
+                    input.events.push(egui::Event::Screenshot {
+                        viewport_id,
+                        image: Arc::new(transform::image_from_buffer(*transform, image)),
+                    });
+                    false
+                }
+                Some(Err(error)) => {
+                    log::error!("Failed to read back screenshot: {error}");
+                    false
+                }
+            });
+    }
+
+    /// Applies the queued changes to the layer surface, returns whether there were any.
+    ///
+    /// They are committed together with the next buffer.
+    pub(crate) fn apply_layer_commands(&mut self) -> bool {
+        if self.layer_commands.is_empty() {
+            return false;
+        }
+        let ShellSurface::Layer(layer) = &self.shell else {
+            // a toplevel's size is up to the compositor
+            self.layer_commands.clear();
+            return false;
+        };
+
+        for command in self.layer_commands.drain(..) {
+            match command {
+                LayerSurfaceCommand::Anchor(anchor) => {
+                    warn_if_exclusive_zone_ignored(layer, self.exclusive_zone, anchor);
+                    self.anchor = anchor
+                }
+                LayerSurfaceCommand::Margin {
+                    top,
+                    right,
+                    bottom,
+                    left,
+                } => {
+                    self.margin = Margin {
+                        top,
+                        right,
+                        bottom,
+                        left,
+                    }
+                }
+                LayerSurfaceCommand::ExclusiveZone(zone) => {
+                    self.exclusive_zone = Some(ExclusiveZone::Fixed(zone))
+                }
+                LayerSurfaceCommand::Size(width, height) => self.requested_size = [width, height],
+                _ => {}
+            }
+            command.apply(layer);
+        }
+        self.update_exclusive_zone();
+        true
+    }
+
+    /// Sets the exclusive zone for the current anchor and size.
+    pub(crate) fn update_exclusive_zone(&self) {
+        let (ShellSurface::Layer(layer), Some(zone)) = (&self.shell, self.exclusive_zone) else {
+            return;
+        };
+        set_exclusive_zone(layer, zone, self.anchor, self.surface_size);
+    }
+
+    /// Resizes the buffers to the surface, with width and height swapped if the buffer
+    /// transform rotates by 90 degrees.
+    pub(crate) fn resize_buffers(&mut self) {
+        let [width, height] = transform::buffer_size(self.buffer_transform, self.surface_size);
+        self.backend.resize(width, height);
+        self.damage_tracker.invalidate();
+    }
+}
+
+/// Takes the input for the next frame of a viewport, leaving what is kept between frames.
+pub(crate) fn take_input(input: &mut egui::RawInput) -> egui::RawInput {
+    let taken = input.take();
+    // kept by `take`, but only sent once
+    for info in input.viewports.values_mut() {
+        info.events.clear();
+    }
+    taken
+}
+
+/// Where the compositor places a surface of the length along one axis of the output.
+fn offset_on_output(anchored: [bool; 2], [start, end]: [i32; 2], length: f32, output: f32) -> f32 {
+    match anchored {
+        [true, false] => start as f32,
+        [false, true] => output - length - end as f32,
+        [true, true] => start as f32 + (output - start as f32 - end as f32 - length) / 2.0,
+        [false, false] => (output - length) / 2.0,
+    }
+}
@@ -0,0 +1,318 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Instant};
+
+use egui::{
+    FullOutput, ImmediateViewport, ViewportBuilder, ViewportClass, ViewportCommand, ViewportId,
+    ViewportIdMap, ViewportIdPair, ViewportOutput,
+};
+use smithay_client_toolkit::shell::wlr_layer::{Anchor, KeyboardInteractivity};
+
+use super::{
+    handle::LayerSurfaceCommand,
+    surface::{take_input, SurfaceOptions},
+    Margin, WgpuLayerShellState,
+};
+use crate::App;
+
+/// Size of child viewports whose builder has no inner size.
+const DEFAULT_VIEWPORT_SIZE: egui::Vec2 = egui::vec2(400.0, 300.0);
+
+/// Immediate viewports are run by egui in the middle of their parent's frame, so their input and
+/// output are passed through here instead of their surfaces.
+#[derive(Default)]
+pub(crate) struct ImmediateViewports {
+    inputs: HashMap<ViewportId, egui::RawInput>,
+    outputs: Vec<(ViewportIdPair, ViewportBuilder, FullOutput)>,
+}
+
+/// Runs immediate viewports with the input of their layer surfaces.
+pub(crate) fn set_immediate_viewport_renderer(immediate: &Rc<RefCell<ImmediateViewports>>) {
+    let immediate = Rc::clone(immediate);
+    egui::Context::set_immediate_viewport_renderer(move |ctx, viewport| {
+        let ImmediateViewport {
+            ids,
+            builder,
+            viewport_ui_cb,
+        } = viewport;
+
+        // kept for the surface, e.g. for another layout pass of the parent
+        let input = immediate
+            .borrow_mut()
+            .inputs
+            .get_mut(&ids.this)
+            .map(take_input);
+        let mut input = input.unwrap_or_else(|| {
+            // the surface gets created after this frame, laid out for the size it asks for
+            let (_, _, [width, height]) = placement(&builder);
+            egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(width as f32, height as f32),
+                )),
+                ..Default::default()
+            }
+        });
+        input.viewport_id = ids.this;
+        // shown together with the parent's frame
+        input.time = Some(ctx.input(|input| input.time));
+
+        let output = ctx.run(input, |ctx| viewport_ui_cb(ctx));
+        immediate.borrow_mut().outputs.push((ids, builder, output));
+    });
+}
+
+/// Where the layer surface of a child viewport goes: with its position as margin from the top
+/// left corner of the output, or centered if it has none.
+fn placement(builder: &ViewportBuilder) -> (Anchor, Margin, [u32; 2]) {
+    let size = builder.inner_size.unwrap_or(DEFAULT_VIEWPORT_SIZE);
+    let size = [size.x, size.y].map(|length| length.round().max(1.0) as u32);
+    match builder.position {
+        Some(position) => (Anchor::TOP | Anchor::LEFT, position_margin(position), size),
+        None => (Anchor::empty(), Margin::default(), size),
+    }
+}
+
+fn position_margin(position: egui::Pos2) -> Margin {
+    Margin {
+        top: position.y.round() as i32,
+        left: position.x.round() as i32,
+        ..Default::default()
+    }
+}
+
+impl WgpuLayerShellState {
+    pub(crate) fn surface_index(&self, viewport_id: ViewportId) -> Option<usize> {
+        self.surfaces
+            .iter()
+            .position(|surface| surface.viewport_id == viewport_id)
+    }
+
+    /// The surface drawing the viewport: immediate viewports are only drawn within the frame of
+    /// their parent.
+    pub(crate) fn drawn_with(&self, mut viewport_id: ViewportId) -> Option<usize> {
+        loop {
+            let index = self.surface_index(viewport_id)?;
+            let surface = &self.surfaces[index];
+            if surface.class != ViewportClass::Immediate {
+                return Some(index);
+            }
+            viewport_id = surface.parent;
+        }
+    }
+
+    /// Creates the layer surface of a child viewport, on the output of its parent.
+    fn create_child_surface(
+        &mut self,
+        ids: ViewportIdPair,
+        class: ViewportClass,
+        builder: &ViewportBuilder,
+    ) -> usize {
+        let (anchor, margin, [width, height]) = placement(builder);
+        let options = SurfaceOptions {
+            width,
+            height,
+            anchor: Some(anchor),
+            margin,
+            exclusive_zone: None,
+            keyboard_interactivity: Some(KeyboardInteractivity::OnDemand),
+            auto_size: None,
+            ..self.surface_options.clone()
+        };
+        let output = self
+            .surface_index(ids.parent)
+            .and_then(|index| self.surfaces[index].output.clone());
+
+        let index = self.create_surface(output, ids.this, &options);
+        let surface = &mut self.surfaces[index];
+        surface.class = class;
+        surface.parent = ids.parent;
+        surface.builder = Some(builder.clone());
+        if let (Some(window), Some(title)) = (surface.shell.toplevel(), &builder.title) {
+            window.set_title(title.clone());
+        }
+        index
+    }
+
+    /// Moves the layer surface of a child viewport if its builder changed.
+    fn update_placement(&mut self, index: usize, builder: &ViewportBuilder) {
+        let surface = &mut self.surfaces[index];
+        let Some(previous) = surface.builder.replace(builder.clone()) else {
+            return;
+        };
+        if placement(&previous) == placement(builder) {
+            return;
+        }
+
+        let (anchor, margin, [width, height]) = placement(builder);
+        surface.layer_commands.extend([
+            LayerSurfaceCommand::Anchor(anchor),
+            LayerSurfaceCommand::Margin {
+                top: margin.top,
+                right: margin.right,
+                bottom: margin.bottom,
+                left: margin.left,
+            },
+            LayerSurfaceCommand::Size(width, height),
+        ]);
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        draw_requests.insert(surface.viewport_id, Instant::now());
+    }
+
+    /// Moves the input of the immediate viewports drawn with the surface to where egui runs
+    /// them from.
+    pub(crate) fn lend_immediate_inputs(&mut self, index: usize) {
+        let lent: Vec<usize> = (0..self.surfaces.len())
+            .filter(|&child| {
+                let surface = &self.surfaces[child];
+                surface.class == ViewportClass::Immediate
+                    && self.drawn_with(surface.viewport_id) == Some(index)
+            })
+            .collect();
+
+        let mut immediate = self.immediate_viewports.borrow_mut();
+        let mut draw_requests = self.draw_requests.write().unwrap();
+        for child in lent {
+            let surface = &mut self.surfaces[child];
+            draw_requests.remove(&surface.viewport_id);
+            immediate
+                .inputs
+                .insert(surface.viewport_id, surface.take_input());
+        }
+    }
+
+    /// Gives the input of the immediate viewports back to their surfaces, without what the shown
+    /// ones consumed, and returns the output of the shown ones.
+    pub(crate) fn take_immediate_outputs(
+        &mut self,
+    ) -> Vec<(ViewportIdPair, ViewportBuilder, FullOutput)> {
+        let mut immediate = self.immediate_viewports.borrow_mut();
+        for (viewport_id, input) in immediate.inputs.drain() {
+            if let Some(surface) = self
+                .surfaces
+                .iter_mut()
+                .find(|surface| surface.viewport_id == viewport_id)
+            {
+                surface.input = input;
+            }
+        }
+        std::mem::take(&mut immediate.outputs)
+    }
+
+    /// Creates, updates and removes the surfaces of child viewports after a frame of `current`.
+    pub(crate) fn update_viewports(
+        &mut self,
+        current: ViewportId,
+        viewport_output: &ViewportIdMap<ViewportOutput>,
+    ) {
+        // not shown anymore, or their parent is gone
+        while let Some(index) = self.surfaces.iter().position(|surface| {
+            surface.class != ViewportClass::Root
+                && !viewport_output.contains_key(&surface.viewport_id)
+        }) {
+            self.remove_surface(index);
+        }
+
+        for (&viewport_id, output) in viewport_output {
+            if output.class == ViewportClass::Deferred {
+                let index = match self.surface_index(viewport_id) {
+                    Some(index) => index,
+                    None => self.create_child_surface(
+                        ViewportIdPair::from_self_and_parent(viewport_id, output.parent),
+                        ViewportClass::Deferred,
+                        &output.builder,
+                    ),
+                };
+                self.update_placement(index, &output.builder);
+                self.surfaces[index].viewport_ui_cb = output.viewport_ui_cb.clone();
+            }
+
+            if let Some(index) = self.surface_index(viewport_id) {
+                self.apply_viewport_commands(index, &output.commands, viewport_id != current);
+            }
+        }
+    }
+
+    /// Maps the viewport commands layer surfaces support onto them.
+    fn apply_viewport_commands(
+        &mut self,
+        index: usize,
+        commands: &[ViewportCommand],
+        needs_redraw: bool,
+    ) {
+        if commands.is_empty() {
+            return;
+        }
+
+        let surface = &mut self.surfaces[index];
+        for command in commands {
+            match command {
+                ViewportCommand::Screenshot => surface.screenshot_requested = true,
+                ViewportCommand::Title(title) => match surface.shell.toplevel() {
+                    Some(window) => window.set_title(title.clone()),
+                    None => log::debug!("Layer surfaces have no title"),
+                },
+                ViewportCommand::InnerSize(size) => {
+                    let [width, height] = [size.x, size.y].map(|length| length.round() as u32);
+                    surface
+                        .layer_commands
+                        .push(LayerSurfaceCommand::Size(width, height));
+                }
+                ViewportCommand::OuterPosition(position) => {
+                    let margin = position_margin(*position);
+                    surface.layer_commands.extend([
+                        LayerSurfaceCommand::Anchor(Anchor::TOP | Anchor::LEFT),
+                        LayerSurfaceCommand::Margin {
+                            top: margin.top,
+                            right: margin.right,
+                            bottom: margin.bottom,
+                            left: margin.left,
+                        },
+                    ]);
+                }
+                command => {
+                    log::debug!("Viewport command not supported on layer surfaces: {command:?}")
+                }
+            }
+        }
+
+        // the current surface applies them with the frame it is about to present
+        if needs_redraw {
+            let mut draw_requests = self.draw_requests.write().unwrap();
+            draw_requests.insert(surface.viewport_id, Instant::now());
+        }
+    }
+
+    /// Drops the output of a layout pass that is not shown, keeping its texture changes.
+    pub(crate) fn discard_pass(&mut self, full_output: &FullOutput) {
+        self.discard_textures(&full_output.textures_delta);
+        let immediate_outputs = std::mem::take(&mut self.immediate_viewports.borrow_mut().outputs);
+        for (_, _, full_output) in immediate_outputs {
+            self.discard_textures(&full_output.textures_delta);
+        }
+    }
+
+    /// Presents the frames of the immediate viewports that ran within the frame of `parent`.
+    pub(crate) fn present_immediate_viewports(
+        &mut self,
+        application: &mut dyn App,
+        outputs: Vec<(ViewportIdPair, ViewportBuilder, FullOutput)>,
+    ) {
+        for (ids, builder, full_output) in outputs {
+            let index = match self.surface_index(ids.this) {
+                Some(index) => {
+                    self.update_placement(index, &builder);
+                    index
+                }
+                None => self.create_child_surface(ids, ViewportClass::Immediate, &builder),
+            };
+
+            let surface = &mut self.surfaces[index];
+            if !surface.is_configured {
+                // drawn with the next frame of the parent, once the surface got its size
+                self.discard_textures(&full_output.textures_delta);
+                continue;
+            }
+            surface.begin_frame();
+            self.present(application, index, full_output, Instant::now());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! wlr-layer-shell bound up to version 5, for `set_exclusive_edge`.
+//!
+//! smithay-client-toolkit binds the layer shell up to version 4 only, these mirror its
+//! `LayerShell` and `LayerSurface` with the same options.
+
+use std::sync::{Arc, Weak};
+
+use smithay_client_toolkit::{
+    compositor::Surface,
+    globals::GlobalData,
+    shell::{
+        wlr_layer::{Anchor, KeyboardInteractivity, Layer},
+        WaylandSurface,
+    },
+};
+use wayland_client::{
+    globals::{BindError, GlobalList},
+    protocol::{wl_output::WlOutput, wl_surface::WlSurface},
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::xdg::shell::client::xdg_popup::XdgPopup;
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::ZwlrLayerShellV1,
+    zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1},
+};
+
+/// The version which added `set_exclusive_edge`.
+const EXCLUSIVE_EDGE_VERSION: u32 = 5;
+
+#[derive(Debug)]
+pub(crate) struct LayerShell {
+    wlr_layer_shell: ZwlrLayerShellV1,
+}
+
+impl LayerShell {
+    /// Binds the `zwlr_layer_shell_v1` global.
+    pub(crate) fn bind<State>(
+        globals: &GlobalList,
+        qh: &QueueHandle<State>,
+    ) -> Result<Self, BindError>
+    where
+        State: Dispatch<ZwlrLayerShellV1, GlobalData> + 'static,
+    {
+        let wlr_layer_shell = globals.bind(qh, 1..=EXCLUSIVE_EDGE_VERSION, GlobalData)?;
+        Ok(Self { wlr_layer_shell })
+    }
+
+    pub(crate) fn create_layer_surface<State>(
+        &self,
+        qh: &QueueHandle<State>,
+        surface: impl Into<Surface>,
+        layer: Layer,
+        namespace: Option<impl Into<String>>,
+        output: Option<&WlOutput>,
+    ) -> LayerSurface
+    where
+        State: Dispatch<ZwlrLayerSurfaceV1, LayerSurfaceData> + 'static,
+    {
+        // events must not be dispatched before the data points to the surface
+        let freeze = qh.freeze();
+        let surface = surface.into();
+        let inner = Arc::new_cyclic(|weak| {
+            let wlr_layer_surface = self.wlr_layer_shell.get_layer_surface(
+                surface.wl_surface(),
+                output,
+                layer.into(),
+                namespace.map(Into::into).unwrap_or_default(),
+                qh,
+                LayerSurfaceData {
+                    inner: weak.clone(),
+                },
+            );
+            LayerSurfaceInner {
+                wl_surface: surface,
+                wlr_layer_surface,
+            }
+        });
+        drop(freeze);
+
+        LayerSurface(inner)
+    }
+}
+
+/// Handles the events of the layer surfaces.
+pub(crate) trait LayerShellHandler: Sized {
+    /// The compositor closed the surface, it is not shown anymore.
+    fn closed(&mut self, conn: &Connection, qh: &QueueHandle<Self>, layer: &LayerSurface);
+
+    /// The compositor suggests a size for the surface, the configure is acknowledged already.
+    fn configure(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        layer: &LayerSurface,
+        configure: LayerSurfaceConfigure,
+        serial: u32,
+    );
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct LayerSurfaceConfigure {
+    /// The suggested size, `0` leaves the length along that axis to the client.
+    pub(crate) new_size: (u32, u32),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct LayerSurface(Arc<LayerSurfaceInner>);
+
+impl PartialEq for LayerSurface {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl LayerSurface {
+    fn wlr_layer_surface(&self) -> &ZwlrLayerSurfaceV1 {
+        &self.0.wlr_layer_surface
+    }
+
+    pub(crate) fn get_popup(&self, popup: &XdgPopup) {
+        self.wlr_layer_surface().get_popup(popup);
+    }
+
+    pub(crate) fn set_size(&self, width: u32, height: u32) {
+        self.wlr_layer_surface().set_size(width, height);
+    }
+
+    pub(crate) fn set_anchor(&self, anchor: Anchor) {
+        // the bits are the same as the protocol's
+        self.wlr_layer_surface()
+            .set_anchor(zwlr_layer_surface_v1::Anchor::from_bits_truncate(
+                anchor.bits(),
+            ));
+    }
+
+    pub(crate) fn set_exclusive_zone(&self, zone: i32) {
+        self.wlr_layer_surface().set_exclusive_zone(zone);
+    }
+
+    /// Whether the compositor supports choosing the edge of the exclusive zone.
+    pub(crate) fn supports_exclusive_edge(&self) -> bool {
+        self.wlr_layer_surface().version() >= EXCLUSIVE_EDGE_VERSION
+    }
+
+    /// Sets the edge the exclusive zone is reserved at, which has to be an anchored one, or
+    /// none to let the compositor deduce it from the anchor.
+    ///
+    /// Ignored if the compositor does not support it.
+    pub(crate) fn set_exclusive_edge(&self, edge: Option<Anchor>) {
+        if self.supports_exclusive_edge() {
+            let edge = edge.unwrap_or(Anchor::empty());
+            self.wlr_layer_surface().set_exclusive_edge(
+                zwlr_layer_surface_v1::Anchor::from_bits_truncate(edge.bits()),
+            );
+        }
+    }
+
+    pub(crate) fn set_margin(&self, top: i32, right: i32, bottom: i32, left: i32) {
+        self.wlr_layer_surface()
+            .set_margin(top, right, bottom, left);
+    }
+
+    pub(crate) fn set_keyboard_interactivity(&self, value: KeyboardInteractivity) {
+        self.wlr_layer_surface()
+            .set_keyboard_interactivity(value.into());
+    }
+
+    pub(crate) fn set_layer(&self, layer: Layer) {
+        self.wlr_layer_surface().set_layer(layer.into());
+    }
+}
+
+impl WaylandSurface for LayerSurface {
+    fn wl_surface(&self) -> &WlSurface {
+        self.0.wl_surface.wl_surface()
+    }
+}
+
+#[derive(Debug)]
+struct LayerSurfaceInner {
+    wl_surface: Surface,
+    wlr_layer_surface: ZwlrLayerSurfaceV1,
+}
+
+impl Drop for LayerSurfaceInner {
+    fn drop(&mut self) {
+        // the role object has to be destroyed before the wl_surface, which `Surface` destroys
+        self.wlr_layer_surface.destroy();
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LayerSurfaceData {
+    inner: Weak<LayerSurfaceInner>,
+}
+
+impl<D> Dispatch<ZwlrLayerShellV1, GlobalData, D> for LayerShell
+where
+    D: Dispatch<ZwlrLayerShellV1, GlobalData>,
+{
+    fn event(
+        _: &mut D,
+        _: &ZwlrLayerShellV1,
+        _: <ZwlrLayerShellV1 as Proxy>::Event,
+        _: &GlobalData,
+        _: &Connection,
+        _: &QueueHandle<D>,
+    ) {
+        unreachable!("zwlr_layer_shell_v1 has no events")
+    }
+}
+
+impl<D> Dispatch<ZwlrLayerSurfaceV1, LayerSurfaceData, D> for LayerShell
+where
+    D: Dispatch<ZwlrLayerSurfaceV1, LayerSurfaceData> + LayerShellHandler,
+{
+    fn event(
+        state: &mut D,
+        wlr_layer_surface: &ZwlrLayerSurfaceV1,
+        event: zwlr_layer_surface_v1::Event,
+        data: &LayerSurfaceData,
+        conn: &Connection,
+        qh: &QueueHandle<D>,
+    ) {
+        let Some(layer) = data.inner.upgrade().map(LayerSurface) else {
+            return;
+        };
+        match event {
+            zwlr_layer_surface_v1::Event::Configure {
+                serial,
+                width,
+                height,
+            } => {
+                wlr_layer_surface.ack_configure(serial);
+                let configure = LayerSurfaceConfigure {
+                    new_size: (width, height),
+                };
+                state.configure(conn, qh, &layer, configure, serial);
+            }
+            zwlr_layer_surface_v1::Event::Closed => state.closed(conn, qh, &layer),
+            _ => {}
+        }
+    }
+}
@@ -1,38 +1,241 @@
 use application::WgpuLayerShellApp;
-use layer_shell::LayerShellOptions;
+use layer_shell::{LayerShellOptions, LayerSurfaceHandle, ShellMode};
+use smithay_client_toolkit::output::OutputInfo;
 
 pub(crate) mod application;
+pub(crate) mod damage;
 pub(crate) mod egui_state;
 pub mod error;
+pub mod fonts;
+pub mod headless;
 pub mod layer_shell;
+pub(crate) mod readback;
+#[cfg(feature = "shm")]
+pub(crate) mod shm_state;
+#[cfg(feature = "shm")]
+pub(crate) mod software_renderer;
+pub mod stats;
+pub(crate) mod transform;
 pub(crate) mod wgpu_state;
 
+pub use stats::{FrameStats, FrameTiming};
+pub use wgpu_state::WgpuSetup;
+
 #[derive(Debug)]
 pub enum Error {
     AppCreation(Box<dyn std::error::Error + Send + Sync>),
     Wgpu(egui_wgpu::WgpuError),
+    /// No output matches [`LayerShellOptions::output`] and the fallback is
+    /// [`OutputFallback::Fail`](layer_shell::OutputFallback::Fail).
+    NoMatchingOutput(layer_shell::OutputSelector),
+    /// The compositor does not support the shell the surfaces have to be shown with, see
+    /// [`LayerShellOptions::shell_mode`](layer_shell::LayerShellOptions::shell_mode).
+    ShellUnavailable(layer_shell::ShellMode),
 }
 
 /// Short for `Result<T, eframe::Error>`.
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
 
-pub type AppCreator = Box<dyn FnOnce(&egui::Context) -> Result<Box<dyn App>, Error>>;
+pub type AppCreator = Box<dyn FnOnce(&CreationContext) -> Result<Box<dyn App>, Error>>;
+
+/// Data that is passed to the [`AppCreator`] when the app is being created.
+pub struct CreationContext {
+    /// The egui context, e.g. for installing image loaders or setting the style.
+    pub egui_ctx: egui::Context,
+
+    /// The wgpu device, queue and egui renderer, like eframe's `wgpu_render_state`.
+    ///
+    /// Use this to create resources for [`egui_wgpu::Callback`]s and store them in
+    /// `wgpu_render_state.renderer.write().callback_resources`.
+    pub wgpu_render_state: Option<egui_wgpu::RenderState>,
+
+    /// Changes the layer surface at runtime, `None` if there is none, e.g. when rendering headless.
+    ///
+    /// Also `None` with [`LayerShellOptions::per_output`], every surface has its own handle
+    /// in [`Frame::layer_surface`] then.
+    pub layer_surface: Option<LayerSurfaceHandle>,
+
+    /// Whether the app is shown on a layer surface or in a toplevel window, see
+    /// [`LayerShellOptions::shell_mode`]. `None` when rendering headless.
+    pub shell_mode: Option<ShellMode>,
+}
+
+/// Information about the integration, passed to [`App::update_with_frame`] every frame.
+pub struct Frame {
+    pub(crate) stats: FrameStats,
+    pub(crate) wgpu_render_state: Option<egui_wgpu::RenderState>,
+    pub(crate) layer_surface: Option<LayerSurfaceHandle>,
+    pub(crate) output: Option<OutputInfo>,
+    pub(crate) shell_mode: Option<ShellMode>,
+}
+
+impl Frame {
+    pub(crate) fn new(wgpu_render_state: Option<egui_wgpu::RenderState>) -> Self {
+        Self {
+            stats: FrameStats::default(),
+            wgpu_render_state,
+            layer_surface: None,
+            output: None,
+            shell_mode: None,
+        }
+    }
+
+    /// CPU and GPU timings of the recent frames.
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    /// The wgpu device, queue and egui renderer, see [`CreationContext::wgpu_render_state`].
+    pub fn wgpu_render_state(&self) -> Option<&egui_wgpu::RenderState> {
+        self.wgpu_render_state.as_ref()
+    }
+
+    /// Changes the layer surface at runtime, see [`CreationContext::layer_surface`].
+    pub fn layer_surface(&self) -> Option<&LayerSurfaceHandle> {
+        self.layer_surface.as_ref()
+    }
+
+    /// The output the surface being drawn is shown on, e.g. to show different content on
+    /// every output with [`LayerShellOptions::per_output`].
+    ///
+    /// `None` until the compositor put the surface on an output, and when rendering headless.
+    pub fn output(&self) -> Option<&OutputInfo> {
+        self.output.as_ref()
+    }
+
+    /// How the surface being drawn is shown, see [`CreationContext::shell_mode`].
+    pub fn shell_mode(&self) -> Option<ShellMode> {
+        self.shell_mode
+    }
+
+    /// Makes a texture owned by the app usable in egui, e.g. with [`egui::Image`].
+    ///
+    /// The texture needs `TEXTURE_BINDING` usage and a filterable format. Returns `None` if
+    /// frames are not rendered with wgpu. After a device loss the id is no longer valid, the
+    /// texture has to be registered again in [`App::on_render_state_recreated`].
+    pub fn register_native_texture(
+        &self,
+        texture: &egui_wgpu::wgpu::TextureView,
+        texture_filter: egui_wgpu::wgpu::FilterMode,
+    ) -> Option<egui::TextureId> {
+        let render_state = self.wgpu_render_state.as_ref()?;
+        Some(render_state.renderer.write().register_native_texture(
+            &render_state.device,
+            texture,
+            texture_filter,
+        ))
+    }
+
+    /// Points a texture id returned by [`Frame::register_native_texture`] to another texture,
+    /// e.g. after its size changed.
+    ///
+    /// Writing to a registered texture in place, e.g. decoding video frames into it, needs no
+    /// update: the parts of the surface showing native textures are redrawn with every frame.
+    ///
+    /// Panics if `id` is not a registered texture.
+    pub fn update_native_texture(
+        &self,
+        id: egui::TextureId,
+        texture: &egui_wgpu::wgpu::TextureView,
+        texture_filter: egui_wgpu::wgpu::FilterMode,
+    ) {
+        if let Some(render_state) = &self.wgpu_render_state {
+            render_state
+                .renderer
+                .write()
+                .update_egui_texture_from_wgpu_texture(
+                    &render_state.device,
+                    texture,
+                    texture_filter,
+                    id,
+                );
+        }
+    }
+
+    /// Releases a texture registered with [`Frame::register_native_texture`].
+    ///
+    /// The texture itself is still owned by the app.
+    pub fn free_native_texture(&self, id: egui::TextureId) {
+        if let Some(render_state) = &self.wgpu_render_state {
+            render_state.renderer.write().free_texture(&id);
+        }
+    }
+}
+
+/// The wgpu objects and targets passed to [`App::pre_render`] and [`App::post_render`].
+pub struct RenderContext<'a> {
+    pub device: &'a egui_wgpu::wgpu::Device,
+    pub queue: &'a egui_wgpu::wgpu::Queue,
+    /// Encoder the egui render pass is recorded into as well, it is submitted after the frame.
+    pub encoder: &'a mut egui_wgpu::wgpu::CommandEncoder,
+    /// The view to render into.
+    ///
+    /// Before egui this is the texture egui renders into, which is multisampled if msaa is
+    /// enabled. After egui it is the surface texture.
+    pub target: &'a egui_wgpu::wgpu::TextureView,
+    pub target_format: egui_wgpu::wgpu::TextureFormat,
+    /// Sample count of `target`, pipelines drawing into it have to use the same count.
+    pub sample_count: u32,
+    /// Size of `target`, which is rotated like the output if the compositor prefers a buffer
+    /// transform, so width and height are swapped on portrait outputs.
+    pub size_in_pixels: [u32; 2],
+    /// The texture egui rendered into, only set for [`App::post_render`].
+    pub source: Option<&'a egui_wgpu::wgpu::TextureView>,
+}
 
 pub trait App {
+    /// Lays out the ui, called every frame.
+    ///
+    /// With [`LayerShellOptions::auto_size`] this is called up to
+    /// [`AutoSize::max_passes`](layer_shell::AutoSize::max_passes) more times in the same
+    /// frame while the surface is resized to fit the content, so it should not count frames or
+    /// otherwise assume it runs once per frame.
     fn update(&mut self, ctx: &egui::Context);
 
+    /// Called every frame instead of [`App::update`], with access to the frame timings and
+    /// the render state.
+    ///
+    /// Apps implementing this can leave [`App::update`] empty. Like it, this may be called
+    /// several times per frame with [`LayerShellOptions::auto_size`].
+    fn update_with_frame(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
+        self.update(ctx);
+    }
+
+    /// Called after the wgpu device got lost and everything was recreated on a new one.
+    ///
+    /// Resources created on the old device, including everything in the renderer's
+    /// callback resources, are gone and have to be created again.
+    fn on_render_state_recreated(&mut self, _render_state: &egui_wgpu::RenderState) {}
+
+    /// Color the surface is cleared to before egui draws on it, as unmultiplied RGBA in gamma space.
+    ///
+    /// This can be used to give the surface a tinted, translucent background without
+    /// drawing a panel covering all of it, e.g. `[0.05, 0.05, 0.05, 0.7]`.
+    fn clear_color(&self, _visuals: &egui::Visuals) -> [f32; 4] {
+        egui::Rgba::TRANSPARENT.to_array()
+    }
+
+    /// Draws below egui, called every frame if [`LayerShellOptions::pre_render`] is set.
+    ///
+    /// The target was cleared to [`App::clear_color`] already, and egui is drawn on top of
+    /// whatever is rendered into it here.
+    fn pre_render(&mut self, _render_context: &mut RenderContext) {}
+
+    /// Post-processes the frame, called every frame if [`LayerShellOptions::post_render`] is set.
+    ///
+    /// egui rendered into the intermediate `source` texture instead of the surface, so this has
+    /// to cover all of `target` with it, e.g. by drawing a fullscreen triangle sampling it.
+    fn post_render(&mut self, _render_context: &mut RenderContext) {}
+
     // fn save(&mut self, _storage: &mut dyn Storage) {}
     // fn on_exit(&mut self) {}
     // fn auto_save_interval(&self) -> std::time::Duration {
     //     std::time::Duration::from_secs(30)
     // }
-    // fn clear_color(&self, _visuals: &egui::Visuals) -> [f32; 4] {
-    //     egui::Color32::from_rgba_unmultiplied(12, 12, 12, 180).to_normalized_gamma_f32()
-    // }
 }
 
 pub fn run_layer(options: LayerShellOptions, app_creator: AppCreator) -> Result {
-    let mut app = WgpuLayerShellApp::new(options, app_creator);
+    let mut app = WgpuLayerShellApp::new(options, app_creator)?;
 
     app.run()
 }
@@ -0,0 +1,119 @@
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use egui::ColorImage;
+use wgpu::{
+    Buffer, BufferAsyncError, BufferDescriptor, BufferUsages, CommandEncoder, Device, Extent3d,
+    ImageCopyBuffer, ImageDataLayout, MapMode, Texture, TextureFormat,
+    COPY_BYTES_PER_ROW_ALIGNMENT,
+};
+
+/// A copy of a rendered texture in a mappable buffer, for reading frames back to the CPU.
+pub(crate) struct TextureReadback {
+    buffer: Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    format: TextureFormat,
+}
+
+impl TextureReadback {
+    /// Records copying the texture into a new buffer, which can be mapped once the
+    /// commands got submitted.
+    ///
+    /// The texture needs `COPY_SRC` usage and a 4 byte rgba or bgra format.
+    pub(crate) fn new(device: &Device, encoder: &mut CommandEncoder, texture: &Texture) -> Self {
+        let (width, height) = (texture.width(), texture.height());
+        let padded_bytes_per_row =
+            (width * 4).div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("egui readback buffer"),
+            size: (padded_bytes_per_row * height) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Self {
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row,
+            format: texture.format(),
+        }
+    }
+
+    /// Starts mapping the buffer, which has to happen after the copy got submitted.
+    pub(crate) fn map(self) -> PendingReadback {
+        let (sender, receiver) = mpsc::channel();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+
+        PendingReadback {
+            readback: self,
+            mapped: receiver,
+        }
+    }
+
+    /// Converts the mapped buffer into an image, the buffer has to be mapped already.
+    fn to_color_image(&self) -> ColorImage {
+        let is_bgra = matches!(
+            self.format,
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb
+        );
+
+        let data = self.buffer.slice(..).get_mapped_range();
+        let mut rgba = Vec::with_capacity((self.width * self.height * 4) as usize);
+        for row in data.chunks_exact(self.padded_bytes_per_row as usize) {
+            for pixel in row[..(self.width * 4) as usize].chunks_exact(4) {
+                if is_bgra {
+                    rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+                } else {
+                    rgba.extend_from_slice(pixel);
+                }
+            }
+        }
+        drop(data);
+        self.buffer.unmap();
+
+        // the surface is composited with premultiplied alpha
+        ColorImage::from_rgba_premultiplied([self.width as usize, self.height as usize], &rgba)
+    }
+}
+
+/// A readback whose buffer is being mapped.
+pub(crate) struct PendingReadback {
+    readback: TextureReadback,
+    mapped: Receiver<Result<(), BufferAsyncError>>,
+}
+
+impl PendingReadback {
+    /// Returns the image once the buffer got mapped, which only happens while polling the device.
+    pub(crate) fn try_take(&self) -> Option<Result<ColorImage, BufferAsyncError>> {
+        match self.mapped.try_recv() {
+            Ok(Ok(())) => Some(Ok(self.readback.to_color_image())),
+            Ok(Err(error)) => Some(Err(error)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(BufferAsyncError)),
+        }
+    }
+}
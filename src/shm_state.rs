@@ -0,0 +1,96 @@
+use egui::{ClippedPrimitive, Color32};
+use smithay_client_toolkit::shm::{
+    slot::{Buffer, SlotPool},
+    CreatePoolError, Shm,
+};
+use wayland_client::protocol::wl_shm;
+
+use crate::software_renderer::SoftwareRenderer;
+
+/// Renders frames on the CPU into shared memory buffers, for machines without a usable GPU.
+pub(crate) struct ShmState {
+    pool: SlotPool,
+    /// At most two buffers, so one can be drawn into while the compositor reads the other.
+    buffers: Vec<Buffer>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl ShmState {
+    pub(crate) fn new(shm: &Shm) -> Result<Self, CreatePoolError> {
+        // the pool grows as soon as the first buffer is created
+        let pool = SlotPool::new(1, shm)?;
+
+        Ok(Self {
+            pool,
+            buffers: Vec::with_capacity(2),
+            width: 1,
+            height: 1,
+        })
+    }
+
+    pub(crate) fn resize(&mut self, width: u32, height: u32) {
+        self.width = width.max(1);
+        self.height = height.max(1);
+        // buffers still attached are released by the compositor once it is done with them
+        self.buffers.clear();
+    }
+
+    pub(crate) fn stride(&self) -> usize {
+        self.width as usize * 4
+    }
+
+    /// Renders into a buffer the compositor is not reading from, returning it and its pixels.
+    ///
+    /// `None` if the compositor still holds on to all buffers.
+    pub(crate) fn render(
+        &mut self,
+        renderer: &mut SoftwareRenderer,
+        clear_color: Color32,
+        primitives: &[ClippedPrimitive],
+        pixels_per_point: f32,
+    ) -> Option<(&Buffer, &[u8])> {
+        let index = self.next_buffer()?;
+        let size = [self.width as usize, self.height as usize];
+        let stride = self.stride();
+
+        let buffer = &self.buffers[index];
+        let canvas = buffer.canvas(&mut self.pool)?;
+        renderer.render(
+            canvas,
+            size,
+            stride,
+            clear_color,
+            primitives,
+            pixels_per_point,
+        );
+        Some((buffer, canvas))
+    }
+
+    /// Index of a buffer that can be drawn into, creating it if there are less than two.
+    fn next_buffer(&mut self) -> Option<usize> {
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.canvas(&mut self.pool).is_some())
+        {
+            return Some(index);
+        }
+        if self.buffers.len() == 2 {
+            return None;
+        }
+
+        let (buffer, _) = self
+            .pool
+            .create_buffer(
+                self.width as i32,
+                self.height as i32,
+                self.stride() as i32,
+                wl_shm::Format::Argb8888,
+            )
+            .inspect_err(|error| log::error!("Failed to create shm buffer: {error}"))
+            .ok()?;
+        self.buffers.push(buffer);
+        Some(self.buffers.len() - 1)
+    }
+}
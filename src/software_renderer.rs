@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use egui::{
+    epaint::{ImageDelta, Primitive, Vertex},
+    ClippedPrimitive, Color32, ImageData, Pos2, Rect, TextureFilter, TextureId,
+};
+
+/// A texture converted into premultiplied colors for sampling on the CPU.
+struct CpuTexture {
+    size: [usize; 2],
+    pixels: Vec<Color32>,
+    filter: TextureFilter,
+}
+
+impl CpuTexture {
+    fn new(image_delta: &ImageDelta) -> Self {
+        let (size, pixels) = match &image_delta.image {
+            ImageData::Color(image) => (image.size, image.pixels.clone()),
+            ImageData::Font(image) => (image.size, image.srgba_pixels(None).collect()),
+        };
+
+        Self {
+            size,
+            pixels,
+            filter: image_delta.options.magnification,
+        }
+    }
+
+    fn texel(&self, x: isize, y: isize) -> [f32; 4] {
+        let x = x.clamp(0, self.size[0] as isize - 1) as usize;
+        let y = y.clamp(0, self.size[1] as isize - 1) as usize;
+        self.pixels[y * self.size[0] + x]
+            .to_array()
+            .map(|channel| channel as f32)
+    }
+
+    fn sample(&self, uv: Pos2) -> [f32; 4] {
+        if self.pixels.is_empty() {
+            return [255.0; 4];
+        }
+
+        let x = uv.x * self.size[0] as f32;
+        let y = uv.y * self.size[1] as f32;
+
+        match self.filter {
+            TextureFilter::Nearest => self.texel(x.floor() as isize, y.floor() as isize),
+            TextureFilter::Linear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+
+                let top = lerp(self.texel(x0, y0), self.texel(x0 + 1, y0), tx);
+                let bottom = lerp(self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1), tx);
+                lerp(top, bottom, ty)
+            }
+        }
+    }
+}
+
+fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t)
+}
+
+/// Rasterizes egui's meshes on the CPU, for surfaces backed by shared memory.
+///
+/// Blending happens in gamma space with premultiplied alpha, like egui's wgpu renderer does
+/// for non-srgb targets. Paint callbacks can not be drawn and are skipped.
+#[derive(Default)]
+pub(crate) struct SoftwareRenderer {
+    textures: HashMap<TextureId, CpuTexture>,
+    warned_about_callbacks: bool,
+}
+
+impl SoftwareRenderer {
+    /// Replaces a texture with its full, current image.
+    pub(crate) fn set_texture(&mut self, id: TextureId, image_delta: &ImageDelta) {
+        self.textures.insert(id, CpuTexture::new(image_delta));
+    }
+
+    pub(crate) fn free_texture(&mut self, id: &TextureId) {
+        self.textures.remove(id);
+    }
+
+    /// Draws the primitives into `pixels`, which are premultiplied ARGB8888 with the given stride.
+    pub(crate) fn render(
+        &mut self,
+        pixels: &mut [u8],
+        [width, height]: [usize; 2],
+        stride: usize,
+        clear_color: Color32,
+        primitives: &[ClippedPrimitive],
+        pixels_per_point: f32,
+    ) {
+        let [r, g, b, a] = clear_color.to_array();
+        for row in pixels.chunks_exact_mut(stride).take(height) {
+            for pixel in row[..width * 4].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[b, g, r, a]);
+            }
+        }
+
+        let screen = Rect::from_min_size(Pos2::ZERO, egui::vec2(width as f32, height as f32));
+        for ClippedPrimitive {
+            clip_rect,
+            primitive,
+        } in primitives
+        {
+            let clip_rect = Rect::from_min_max(
+                (clip_rect.min * pixels_per_point).round(),
+                (clip_rect.max * pixels_per_point).round(),
+            )
+            .intersect(screen);
+            if !clip_rect.is_positive() {
+                continue;
+            }
+
+            match primitive {
+                Primitive::Mesh(mesh) => {
+                    let Some(texture) = self.textures.get(&mesh.texture_id) else {
+                        continue;
+                    };
+                    let mut canvas = Canvas {
+                        pixels: &mut *pixels,
+                        stride,
+                        clip_rect,
+                    };
+                    for triangle in mesh.indices.chunks_exact(3) {
+                        let vertices = [0, 1, 2].map(|i| {
+                            let vertex = mesh.vertices[triangle[i] as usize];
+                            Vertex {
+                                pos: (vertex.pos.to_vec2() * pixels_per_point).to_pos2(),
+                                ..vertex
+                            }
+                        });
+                        canvas.fill_triangle(vertices, texture);
+                    }
+                }
+                Primitive::Callback(_) => {
+                    if !self.warned_about_callbacks {
+                        log::warn!("paint callbacks are not supported by the shm renderer");
+                        self.warned_about_callbacks = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Canvas<'a> {
+    pixels: &'a mut [u8],
+    stride: usize,
+    /// In pixels, rounded and within the canvas.
+    clip_rect: Rect,
+}
+
+impl Canvas<'_> {
+    fn fill_triangle(&mut self, [mut v0, mut v1, v2]: [Vertex; 3], texture: &CpuTexture) {
+        let mut area = edge(v0.pos, v1.pos, v2.pos);
+        if area == 0.0 {
+            return;
+        }
+        // egui does not care about the winding order
+        if area < 0.0 {
+            std::mem::swap(&mut v0, &mut v1);
+            area = -area;
+        }
+
+        let min = v0.pos.min(v1.pos).min(v2.pos).max(self.clip_rect.min);
+        let max = v0.pos.max(v1.pos).max(v2.pos).min(self.clip_rect.max);
+        if min.x >= max.x || min.y >= max.y {
+            return;
+        }
+
+        // the edges opposite of each vertex, pixels exactly on an edge shared by two triangles
+        // must only be blended once, so only one of the two directions includes them
+        let edges = [(v1.pos, v2.pos), (v2.pos, v0.pos), (v0.pos, v1.pos)];
+        let includes_boundary = edges.map(|(a, b)| {
+            let direction = b - a;
+            direction.y > 0.0 || (direction.y == 0.0 && direction.x > 0.0)
+        });
+        let colors = [v0, v1, v2].map(|vertex| vertex.color.to_array().map(|c| c as f32));
+
+        for y in min.y.floor() as usize..max.y.ceil() as usize {
+            for x in min.x.floor() as usize..max.x.ceil() as usize {
+                let center = Pos2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let weights = [0, 1, 2].map(|i| edge(edges[i].0, edges[i].1, center));
+                let is_outside = (0..3)
+                    .any(|i| weights[i] < 0.0 || (weights[i] == 0.0 && !includes_boundary[i]));
+                if is_outside {
+                    continue;
+                }
+
+                let [w0, w1, w2] = weights.map(|weight| weight / area);
+                let uv =
+                    (v0.uv.to_vec2() * w0 + v1.uv.to_vec2() * w1 + v2.uv.to_vec2() * w2).to_pos2();
+                let texel = texture.sample(uv);
+                let [r, g, b, a]: [f32; 4] = std::array::from_fn(|i| {
+                    (colors[0][i] * w0 + colors[1][i] * w1 + colors[2][i] * w2) * texel[i] / 255.0
+                });
+
+                // ARGB8888 is stored as BGRA in memory
+                let offset = y * self.stride + x * 4;
+                let inverse_alpha = 1.0 - a / 255.0;
+                for (destination, source) in
+                    self.pixels[offset..offset + 4].iter_mut().zip([b, g, r, a])
+                {
+                    let blended = source + *destination as f32 * inverse_alpha;
+                    *destination = blended.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `p`, positive if `p` is right of `a` to `b`.
+fn edge(a: Pos2, b: Pos2, p: Pos2) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2, ColorImage, Mesh, TextureOptions};
+
+    use super::*;
+
+    const SIZE: [usize; 2] = [4, 2];
+    /// Wider than a row, to check the padding is left alone.
+    const STRIDE: usize = 20;
+    const CLEAR: [u8; 4] = [0, 0, 0, 255];
+
+    fn renderer_with_texture(image: ColorImage) -> SoftwareRenderer {
+        let mut renderer = SoftwareRenderer::default();
+        renderer.set_texture(
+            TextureId::Managed(0),
+            &ImageDelta::full(image, TextureOptions::NEAREST),
+        );
+        renderer
+    }
+
+    fn rect_mesh(rect: Rect, color: Color32) -> Primitive {
+        let mut mesh = Mesh::with_texture(TextureId::Managed(0));
+        mesh.add_rect_with_uv(
+            rect,
+            Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
+            color,
+        );
+        Primitive::Mesh(mesh)
+    }
+
+    fn render(renderer: &mut SoftwareRenderer, primitives: &[ClippedPrimitive]) -> Vec<u8> {
+        let mut pixels = vec![7; STRIDE * SIZE[1]];
+        let [b, g, r, a] = CLEAR;
+        renderer.render(
+            &mut pixels,
+            SIZE,
+            STRIDE,
+            Color32::from_rgba_premultiplied(r, g, b, a),
+            primitives,
+            1.0,
+        );
+        pixels
+    }
+
+    fn pixel(pixels: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let offset = y * STRIDE + x * 4;
+        pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn blends_a_solid_mesh() {
+        let mut renderer = renderer_with_texture(ColorImage::new([1, 1], Color32::WHITE));
+        let primitives = [ClippedPrimitive {
+            clip_rect: Rect::EVERYTHING,
+            primitive: rect_mesh(
+                Rect::from_min_max(pos2(1.0, 0.0), pos2(3.0, 2.0)),
+                Color32::from_rgba_premultiplied(128, 0, 0, 128),
+            ),
+        }];
+        let pixels = render(&mut renderer, &primitives);
+
+        for y in 0..2 {
+            assert_eq!(pixel(&pixels, 0, y), CLEAR);
+            assert_eq!(pixel(&pixels, 1, y), [0, 0, 128, 255]);
+            assert_eq!(pixel(&pixels, 2, y), [0, 0, 128, 255]);
+            assert_eq!(pixel(&pixels, 3, y), CLEAR);
+            assert!(pixels[y * STRIDE + 16..(y + 1) * STRIDE]
+                .iter()
+                .all(|byte| *byte == 7));
+        }
+    }
+
+    #[test]
+    fn samples_a_texture() {
+        let mut renderer = renderer_with_texture(ColorImage {
+            size: [2, 1],
+            pixels: vec![
+                Color32::RED,
+                Color32::from_rgba_premultiplied(0, 0, 128, 128),
+            ],
+        });
+        let primitives = [ClippedPrimitive {
+            clip_rect: Rect::EVERYTHING,
+            primitive: rect_mesh(
+                Rect::from_min_size(Pos2::ZERO, vec2(4.0, 2.0)),
+                Color32::WHITE,
+            ),
+        }];
+        let pixels = render(&mut renderer, &primitives);
+
+        for y in 0..2 {
+            for x in 0..2 {
+                assert_eq!(pixel(&pixels, x, y), [0, 0, 255, 255]);
+            }
+            for x in 2..4 {
+                assert_eq!(pixel(&pixels, x, y), [128, 0, 0, 255]);
+            }
+        }
+    }
+
+    #[test]
+    fn clips_to_the_clip_rect() {
+        let mut renderer = renderer_with_texture(ColorImage::new([1, 1], Color32::WHITE));
+        let primitives = [ClippedPrimitive {
+            clip_rect: Rect::from_min_max(pos2(0.0, 1.0), pos2(3.0, 2.0)),
+            primitive: rect_mesh(
+                Rect::from_min_size(Pos2::ZERO, vec2(4.0, 2.0)),
+                Color32::GREEN,
+            ),
+        }];
+        let pixels = render(&mut renderer, &primitives);
+
+        for x in 0..4 {
+            assert_eq!(pixel(&pixels, x, 0), CLEAR);
+        }
+        for x in 0..3 {
+            assert_eq!(pixel(&pixels, x, 1), [0, 255, 0, 255]);
+        }
+        assert_eq!(pixel(&pixels, 3, 1), CLEAR);
+    }
+
+    #[test]
+    fn skips_meshes_with_unknown_textures() {
+        let mut renderer = SoftwareRenderer::default();
+        let primitives = [ClippedPrimitive {
+            clip_rect: Rect::EVERYTHING,
+            primitive: rect_mesh(
+                Rect::from_min_size(Pos2::ZERO, vec2(4.0, 2.0)),
+                Color32::WHITE,
+            ),
+        }];
+        let pixels = render(&mut renderer, &primitives);
+
+        for y in 0..2 {
+            for x in 0..4 {
+                assert_eq!(pixel(&pixels, x, y), CLEAR);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use egui::{Align2, Context, RichText};
+use wgpu::{
+    Buffer, BufferAddress, BufferAsyncError, BufferDescriptor, BufferUsages, CommandEncoder,
+    Device, MapMode, QuerySet, QuerySetDescriptor, QueryType, Queue, RenderPassTimestampWrites,
+    QUERY_SIZE,
+};
+
+/// Number of frames [`FrameStats`] keeps the timings of.
+const FRAME_STATS_CAPACITY: usize = 120;
+
+/// How long the phases of a single frame took on the CPU and the GPU, in seconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameTiming {
+    /// Counts up with every drawn frame.
+    pub frame_nr: u64,
+    /// When the frame started, in seconds since the app started, like egui's `input.time`.
+    pub start_time: f64,
+    /// Running the app's `update` and the rest of the egui pass.
+    pub run_ui: f32,
+    /// Turning egui's shapes into triangles.
+    pub tessellate: f32,
+    /// Uploading changed textures as well as the vertex and index buffers.
+    pub upload: f32,
+    /// Recording the render passes and submitting them.
+    pub render: f32,
+    /// The whole frame on the CPU, including acquiring the surface texture and presenting.
+    pub total: f32,
+    /// The egui render pass on the GPU.
+    ///
+    /// `None` if the adapter does not support timestamp queries, or while the measurement
+    /// is still being read back, which takes a frame or two.
+    pub gpu: Option<f32>,
+}
+
+/// Timings of the most recent frames.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    timings: VecDeque<FrameTiming>,
+    frame_count: u64,
+}
+
+impl FrameStats {
+    /// Timings of the recent frames, from oldest to newest.
+    pub fn timings(&self) -> impl ExactSizeIterator<Item = &FrameTiming> + '_ {
+        self.timings.iter()
+    }
+
+    pub fn latest(&self) -> Option<&FrameTiming> {
+        self.timings.back()
+    }
+
+    /// Frames per second, averaged over the recent frames.
+    ///
+    /// Only frames that were drawn count, so this is low when nothing changes on screen.
+    pub fn fps(&self) -> Option<f32> {
+        let first = self.timings.front()?;
+        let last = self.timings.back()?;
+        let elapsed = last.start_time - first.start_time;
+        (elapsed > 0.0).then(|| ((self.timings.len() - 1) as f64 / elapsed) as f32)
+    }
+
+    /// Mean CPU time of the recent frames.
+    pub fn mean_frame_time(&self) -> Option<f32> {
+        mean(self.timings.iter().map(|timing| timing.total))
+    }
+
+    /// Mean GPU time of the recent frames that have one.
+    pub fn mean_gpu_time(&self) -> Option<f32> {
+        mean(self.timings.iter().filter_map(|timing| timing.gpu))
+    }
+
+    /// Adds the timing of a new frame, returning its frame number.
+    pub(crate) fn push(&mut self, mut timing: FrameTiming) -> u64 {
+        timing.frame_nr = self.frame_count;
+        self.frame_count += 1;
+
+        if self.timings.len() == FRAME_STATS_CAPACITY {
+            self.timings.pop_front();
+        }
+        self.timings.push_back(timing);
+
+        timing.frame_nr
+    }
+
+    /// Fills in the GPU time of a frame once it got read back.
+    pub(crate) fn set_gpu_time(&mut self, frame_nr: u64, gpu: f32) {
+        if let Some(timing) = self
+            .timings
+            .iter_mut()
+            .rev()
+            .find(|timing| timing.frame_nr == frame_nr)
+        {
+            timing.gpu = Some(gpu);
+        }
+    }
+}
+
+fn mean(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+/// Measures the egui render pass with timestamp queries.
+///
+/// There is only one readback buffer, so frames are skipped while it is still being mapped.
+pub(crate) struct GpuTimer {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    /// Whether the timestamps of the current frame are being written.
+    is_recording: bool,
+    pending: Option<(u64, Receiver<Result<(), BufferAsyncError>>)>,
+}
+
+impl GpuTimer {
+    /// Creates a timer if the device got created with timestamp queries enabled.
+    pub(crate) fn new(device: &Device, queue: &Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+
+        let size = 2 * QUERY_SIZE as BufferAddress;
+        Some(Self {
+            query_set: device.create_query_set(&QuerySetDescriptor {
+                label: Some("egui timestamp queries"),
+                ty: QueryType::Timestamp,
+                count: 2,
+            }),
+            resolve_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("egui timestamp resolve buffer"),
+                size,
+                usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("egui timestamp readback buffer"),
+                size,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            period: queue.get_timestamp_period(),
+            is_recording: false,
+            pending: None,
+        })
+    }
+
+    /// Timestamp writes for the egui render pass, unless the previous ones are still read back.
+    pub(crate) fn timestamp_writes(&mut self) -> Option<RenderPassTimestampWrites<'_>> {
+        self.is_recording = self.pending.is_none();
+        self.is_recording.then_some(RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(0),
+            end_of_pass_write_index: Some(1),
+        })
+    }
+
+    /// Records copying the timestamps into the readback buffer, after the render pass ended.
+    pub(crate) fn resolve(&self, encoder: &mut CommandEncoder) {
+        if self.is_recording {
+            encoder.resolve_query_set(&self.query_set, 0..2, &self.resolve_buffer, 0);
+            encoder.copy_buffer_to_buffer(
+                &self.resolve_buffer,
+                0,
+                &self.readback_buffer,
+                0,
+                self.resolve_buffer.size(),
+            );
+        }
+    }
+
+    /// Starts reading back the timestamps of the given frame, after it got submitted.
+    pub(crate) fn map(&mut self, frame_nr: u64) {
+        if !std::mem::take(&mut self.is_recording) {
+            return;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.readback_buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+        self.pending = Some((frame_nr, receiver));
+    }
+
+    /// Returns the frame number and GPU time of the last measurement, once it got read back.
+    pub(crate) fn try_take(&mut self) -> Option<(u64, f32)> {
+        let (frame_nr, receiver) = self.pending.as_ref()?;
+        let result = match receiver.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(BufferAsyncError),
+        };
+        let frame_nr = *frame_nr;
+        self.pending = None;
+
+        if let Err(error) = result {
+            log::warn!("Failed to read back timestamp queries: {error}");
+            return None;
+        }
+
+        let data = self.readback_buffer.slice(..).get_mapped_range();
+        let [start, end] = [0, 1].map(|index| {
+            let bytes = &data[index * QUERY_SIZE as usize..][..QUERY_SIZE as usize];
+            u64::from_ne_bytes(bytes.try_into().unwrap())
+        });
+        drop(data);
+        self.readback_buffer.unmap();
+
+        let nanoseconds = end.saturating_sub(start) as f32 * self.period;
+        Some((frame_nr, nanoseconds / 1e9))
+    }
+}
+
+/// Shows the frame rate, frame times and why the frame was drawn in a corner of the screen.
+pub(crate) fn show_overlay(ctx: &Context, stats: &FrameStats) {
+    let milliseconds = |seconds: Option<f32>| match seconds {
+        Some(seconds) => format!("{:.2} ms", seconds * 1000.0),
+        None => "-".to_owned(),
+    };
+
+    let repaint_reason = if ctx.input(|input| !input.raw.events.is_empty()) {
+        "input".to_owned()
+    } else {
+        match ctx.repaint_causes().first() {
+            Some(cause) => cause.to_string(),
+            None => "-".to_owned(),
+        }
+    };
+
+    egui::Area::new(egui::Id::new("layer_shell_frame_stats"))
+        .anchor(Align2::RIGHT_TOP, egui::vec2(-8.0, 8.0))
+        .order(egui::Order::Debug)
+        .interactable(false)
+        .show(ctx, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                egui::Grid::new("frame_stats").show(ui, |ui| {
+                    let mut row = |label: &str, value: String| {
+                        ui.label(label);
+                        ui.label(RichText::new(value).monospace());
+                        ui.end_row();
+                    };
+                    row(
+                        "fps",
+                        stats
+                            .fps()
+                            .map_or_else(|| "-".to_owned(), |fps| format!("{fps:.1}")),
+                    );
+                    row("cpu", milliseconds(stats.mean_frame_time()));
+                    row("gpu", milliseconds(stats.mean_gpu_time()));
+                    row("repaint", repaint_reason);
+                });
+            });
+        });
+}
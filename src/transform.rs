@@ -0,0 +1,239 @@
+use egui::{epaint::Primitive, pos2, ClippedPrimitive, Color32, ColorImage, Pos2, Rect, Vec2};
+use wayland_client::protocol::wl_output::Transform;
+
+/// Whether the buffer is taller than wide for a wide surface and the other way round.
+pub(crate) fn swaps_axes(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
+
+/// Size of the buffer for a surface of the given size.
+pub(crate) fn buffer_size(transform: Transform, [width, height]: [u32; 2]) -> [u32; 2] {
+    if swaps_axes(transform) {
+        [height, width]
+    } else {
+        [width, height]
+    }
+}
+
+/// Maps a position on a surface of the given size to where it is drawn into the buffer, which
+/// the compositor transforms back with the inverse of `transform`.
+pub(crate) fn to_buffer(transform: Transform, Pos2 { x, y }: Pos2, size: Vec2) -> Pos2 {
+    let Vec2 { x: w, y: h } = size;
+    match transform {
+        // the rotations are counter-clockwise
+        Transform::_90 => pos2(y, w - x),
+        Transform::_180 => pos2(w - x, h - y),
+        Transform::_270 => pos2(h - y, x),
+        Transform::Flipped => pos2(w - x, y),
+        Transform::Flipped90 => pos2(y, x),
+        Transform::Flipped180 => pos2(x, h - y),
+        Transform::Flipped270 => pos2(h - y, w - x),
+        _ => pos2(x, y),
+    }
+}
+
+pub(crate) fn rect_to_buffer(transform: Transform, rect: Rect, size: Vec2) -> Rect {
+    Rect::from_two_pos(
+        to_buffer(transform, rect.min, size),
+        to_buffer(transform, rect.max, size),
+    )
+}
+
+/// Moves tessellated meshes into buffer coordinates, so egui is drawn pre-transformed.
+///
+/// Paint callbacks get their rects moved, but draw their content untransformed.
+pub(crate) fn primitives_to_buffer(
+    transform: Transform,
+    primitives: &mut [ClippedPrimitive],
+    size: Vec2,
+) {
+    if transform == Transform::Normal {
+        return;
+    }
+
+    for ClippedPrimitive {
+        clip_rect,
+        primitive,
+    } in primitives
+    {
+        *clip_rect = rect_to_buffer(transform, *clip_rect, size);
+        match primitive {
+            Primitive::Mesh(mesh) => {
+                for vertex in &mut mesh.vertices {
+                    vertex.pos = to_buffer(transform, vertex.pos, size);
+                }
+            }
+            Primitive::Callback(callback) => {
+                callback.rect = rect_to_buffer(transform, callback.rect, size);
+            }
+        }
+    }
+}
+
+/// Turns an image of a transformed buffer back into the orientation of the surface.
+pub(crate) fn image_from_buffer(transform: Transform, image: ColorImage) -> ColorImage {
+    if transform == Transform::Normal {
+        return image;
+    }
+
+    let [width, height] = buffer_size(transform, [image.width() as u32, image.height() as u32])
+        .map(|size| size as usize);
+    let size = egui::vec2(width as f32, height as f32);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let center = pos2(x as f32 + 0.5, y as f32 + 0.5);
+            let source = to_buffer(transform, center, size);
+            let index = source.y as usize * image.width() + source.x as usize;
+            pixels.push(
+                image
+                    .pixels
+                    .get(index)
+                    .copied()
+                    .unwrap_or(Color32::TRANSPARENT),
+            );
+        }
+    }
+
+    ColorImage {
+        size: [width, height],
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::vec2;
+
+    use super::*;
+
+    const TRANSFORMS: [Transform; 8] = [
+        Transform::Normal,
+        Transform::_90,
+        Transform::_180,
+        Transform::_270,
+        Transform::Flipped,
+        Transform::Flipped90,
+        Transform::Flipped180,
+        Transform::Flipped270,
+    ];
+
+    fn corners(size: Vec2) -> [Pos2; 4] {
+        [
+            pos2(0.0, 0.0),
+            pos2(size.x, 0.0),
+            pos2(0.0, size.y),
+            pos2(size.x, size.y),
+        ]
+    }
+
+    /// Draws a surface image into a buffer, the way the renderer does with `to_buffer`.
+    fn image_to_buffer(transform: Transform, image: &ColorImage) -> ColorImage {
+        let [width, height] = buffer_size(transform, [image.width() as u32, image.height() as u32])
+            .map(|size| size as usize);
+        let size = vec2(image.width() as f32, image.height() as f32);
+        let mut pixels = vec![Color32::TRANSPARENT; width * height];
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let center = pos2(x as f32 + 0.5, y as f32 + 0.5);
+                let target = to_buffer(transform, center, size);
+                pixels[target.y as usize * width + target.x as usize] =
+                    image.pixels[y * image.width() + x];
+            }
+        }
+        ColorImage {
+            size: [width, height],
+            pixels,
+        }
+    }
+
+    fn test_image() -> ColorImage {
+        ColorImage {
+            size: [3, 2],
+            pixels: (0..6).map(|i| Color32::from_gray(i * 40)).collect(),
+        }
+    }
+
+    #[test]
+    fn buffer_size_swaps_axes_for_quarter_turns() {
+        for transform in TRANSFORMS {
+            let expected = if swaps_axes(transform) {
+                [2, 3]
+            } else {
+                [3, 2]
+            };
+            assert_eq!(buffer_size(transform, [3, 2]), expected, "{transform:?}");
+        }
+    }
+
+    #[test]
+    fn to_buffer_maps_corners_to_buffer_corners() {
+        let size = vec2(4.0, 2.0);
+        for transform in TRANSFORMS {
+            let [width, height] = buffer_size(transform, [4, 2]).map(|size| size as f32);
+            let buffer_corners = corners(vec2(width, height));
+            let mut mapped: Vec<Pos2> = corners(size)
+                .into_iter()
+                .map(|corner| to_buffer(transform, corner, size))
+                .collect();
+            for corner in buffer_corners {
+                let index = mapped.iter().position(|pos| *pos == corner);
+                assert!(index.is_some(), "{transform:?} misses {corner:?}");
+                mapped.remove(index.unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn rect_to_buffer_maps_the_surface_to_the_buffer() {
+        let size = vec2(4.0, 2.0);
+        for transform in TRANSFORMS {
+            let [width, height] = buffer_size(transform, [4, 2]).map(|size| size as f32);
+            assert_eq!(
+                rect_to_buffer(transform, Rect::from_min_size(Pos2::ZERO, size), size),
+                Rect::from_min_size(Pos2::ZERO, vec2(width, height)),
+                "{transform:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn image_from_buffer_undoes_to_buffer() {
+        let image = test_image();
+        for transform in TRANSFORMS {
+            let buffer = image_to_buffer(transform, &image);
+            assert_eq!(image_from_buffer(transform, buffer), image, "{transform:?}");
+        }
+    }
+
+    #[test]
+    fn half_turns_and_flips_reverse_pixels() {
+        let image = test_image();
+        let reversed = |rows: bool, columns: bool| {
+            let mut pixels = Vec::new();
+            for y in 0..2 {
+                for x in 0..3 {
+                    let y = if rows { 1 - y } else { y };
+                    let x = if columns { 2 - x } else { x };
+                    pixels.push(image.pixels[y * 3 + x]);
+                }
+            }
+            pixels
+        };
+        assert_eq!(
+            image_to_buffer(Transform::_180, &image).pixels,
+            reversed(true, true)
+        );
+        assert_eq!(
+            image_to_buffer(Transform::Flipped, &image).pixels,
+            reversed(false, true)
+        );
+        assert_eq!(
+            image_to_buffer(Transform::Flipped180, &image).pixels,
+            reversed(true, false)
+        );
+    }
+}
@@ -1,5 +1,13 @@
-use std::ptr::NonNull;
+use std::{
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+use egui::mutex::RwLock;
+use egui_wgpu::{RenderState, Renderer};
 use raw_window_handle::{
     RawDisplayHandle, RawWindowHandle, WaylandDisplayHandle, WaylandWindowHandle,
 };
@@ -7,11 +15,16 @@ use thiserror::Error;
 use wayland_backend::client::Backend;
 use wayland_client::{protocol::wl_surface::WlSurface, Proxy};
 use wgpu::{
-    Backends, CreateSurfaceError, Device, Instance, InstanceDescriptor, PresentMode, Queue,
-    RequestAdapterOptions, RequestDeviceError, Surface, SurfaceConfiguration, SurfaceTargetUnsafe,
-    TextureFormat, TextureUsages,
+    Adapter, Backends, Color, CompositeAlphaMode, CreateSurfaceError, Device, DeviceDescriptor,
+    DeviceLostReason, DeviceType, Extent3d, Features, Instance, InstanceDescriptor,
+    PowerPreference, PresentMode, Queue, RequestAdapterOptions, RequestDeviceError, Surface,
+    SurfaceConfiguration, SurfaceTargetUnsafe, Texture, TextureDescriptor, TextureDimension,
+    TextureFormat, TextureFormatFeatureFlags, TextureFormatFeatures, TextureUsages, TextureView,
 };
 
+use crate::stats::GpuTimer;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum WgpuStateError {
     #[error("Pointer to {0} is null")]
@@ -24,48 +37,192 @@ pub enum WgpuStateError {
     NoDeviceError(#[from] RequestDeviceError),
     #[error("Failed to select proper surface texture format")]
     NoTextureFormatError,
+    #[error("The provided adapter can not present to the surface")]
+    IncompatibleAdapterError,
+    #[error("{0:?} can not be used as depth buffer")]
+    DepthFormatError(TextureFormat),
+}
+
+/// How the wgpu instance, adapter and device are obtained.
+#[derive(Clone, Debug, Default)]
+pub enum WgpuSetup {
+    /// Create new ones, according to the adapter selection options.
+    #[default]
+    CreateNew,
+    /// Reuse wgpu objects owned by the app, e.g. to share buffers with the UI.
+    ///
+    /// The adapter has to be able to present to the layer surface. Device loss is not handled
+    /// for these, since the device belongs to the app.
+    Existing {
+        instance: Arc<Instance>,
+        adapter: Arc<Adapter>,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    },
+}
+
+/// Settings for creating a [`WgpuState`], kept around to recreate it after a device loss.
+#[derive(Clone, Debug)]
+pub(crate) struct WgpuOptions {
+    /// Requested number of samples per pixel, may be lowered if the adapter does not support it.
+    pub(crate) msaa_samples: u32,
+    /// Format of the depth buffer attached to the egui render pass, if any.
+    pub(crate) depth_format: Option<TextureFormat>,
+    /// Backends the instance is allowed to use, overridden by `WGPU_BACKEND`.
+    pub(crate) backends: Backends,
+    pub(crate) power_preference: PowerPreference,
+    pub(crate) force_fallback_adapter: bool,
+    /// Case insensitive substring of the adapter name, overridden by `WGPU_ADAPTER_NAME`.
+    pub(crate) adapter_name: Option<String>,
+    pub(crate) setup: WgpuSetup,
+    /// Render egui into an intermediate texture instead of the surface, for post-processing.
+    pub(crate) post_render: bool,
+}
+
+impl WgpuOptions {
+    /// Applies the `WGPU_BACKEND` and `WGPU_ADAPTER_NAME` environment overrides.
+    pub(crate) fn with_env_overrides(mut self) -> Self {
+        if let Some(backends) = wgpu::util::backend_bits_from_env() {
+            self.backends = backends;
+        }
+        if let Ok(adapter_name) = std::env::var("WGPU_ADAPTER_NAME") {
+            self.adapter_name = Some(adapter_name);
+        }
+        self
+    }
+}
+
+/// The wgpu objects all surfaces share, so they can draw with the same egui renderer.
+#[derive(Clone)]
+pub(crate) struct SharedDevice {
+    instance: Arc<Instance>,
+    adapter: Arc<Adapter>,
+    available_adapters: Arc<[Adapter]>,
+    pub(crate) device: Arc<Device>,
+    pub(crate) queue: Arc<Queue>,
+    device_lost: Arc<AtomicBool>,
+    options: WgpuOptions,
+}
+
+impl SharedDevice {
+    pub(crate) fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
+    }
 }
 
 pub struct WgpuState {
-    pub(crate) device: Device,
+    // the instance is kept alive for the lifetime of the device and surface
+    instance: Arc<Instance>,
+    pub(crate) adapter: Arc<Adapter>,
+    available_adapters: Arc<[Adapter]>,
+    pub(crate) device: Arc<Device>,
     pub(crate) surface_configuration: SurfaceConfiguration,
-    pub(crate) queue: Queue,
+    pub(crate) queue: Arc<Queue>,
     pub(crate) surface: Surface<'static>,
+    /// Number of samples per pixel actually used for rendering.
+    pub(crate) msaa_samples: u32,
+    /// Multisampled color target which gets resolved into the surface texture, if msaa is enabled.
+    pub(crate) msaa_texture_view: Option<TextureView>,
+    /// Depth buffer matching the surface size, if a depth format was requested.
+    pub(crate) depth_texture_view: Option<TextureView>,
+    /// Texture egui renders into before it gets post-processed onto the surface, if requested.
+    pub(crate) post_render_texture_view: Option<TextureView>,
+    /// Measures the egui render pass, if the device supports timestamp queries.
+    pub(crate) gpu_timer: Option<GpuTimer>,
+    options: WgpuOptions,
+    device_lost: Arc<AtomicBool>,
 }
 
 impl WgpuState {
-    pub fn new(backend: &Backend, wl_surface: &WlSurface) -> Result<Self, WgpuStateError> {
-        let instance = Instance::new(InstanceDescriptor {
-            backends: Backends::all(),
-            ..Default::default()
-        });
+    pub fn new(
+        backend: &Backend,
+        wl_surface: &WlSurface,
+        options: WgpuOptions,
+    ) -> Result<Self, WgpuStateError> {
+        let selection = options.clone().with_env_overrides();
+        let instance = match &options.setup {
+            WgpuSetup::CreateNew => Arc::new(Instance::new(InstanceDescriptor {
+                backends: selection.backends,
+                ..Default::default()
+            })),
+            WgpuSetup::Existing { instance, .. } => Arc::clone(instance),
+        };
+
+        let surface = create_surface(&instance, backend, wl_surface)?;
 
-        let raw_display_handle = RawDisplayHandle::Wayland(WaylandDisplayHandle::new(
-            NonNull::new(backend.display_ptr() as *mut _).ok_or(
-                WgpuStateError::NullPointerError("display of backend".to_string()),
-            )?,
-        ));
-        let raw_window_handle = RawWindowHandle::Wayland(WaylandWindowHandle::new(
-            NonNull::new(wl_surface.id().as_ptr() as *mut _).ok_or(
-                WgpuStateError::NullPointerError("wl_surface id".to_string()),
-            )?,
-        ));
-
-        let surface = unsafe {
-            instance.create_surface_unsafe(SurfaceTargetUnsafe::RawHandle {
-                raw_display_handle,
-                raw_window_handle,
-            })?
+        let (adapter, device, queue, device_lost) = match &options.setup {
+            WgpuSetup::CreateNew => {
+                let adapter = request_adapter(&instance, &selection, Some(&surface))?;
+                let (device, queue, device_lost) = request_device(&adapter, &options)?;
+                (
+                    Arc::new(adapter),
+                    Arc::new(device),
+                    Arc::new(queue),
+                    device_lost,
+                )
+            }
+            WgpuSetup::Existing {
+                adapter,
+                device,
+                queue,
+                ..
+            } => {
+                if !adapter.is_surface_supported(&surface) {
+                    return Err(WgpuStateError::IncompatibleAdapterError);
+                }
+                log::info!(
+                    "using provided wgpu adapter: {}",
+                    egui_wgpu::adapter_info_summary(&adapter.get_info())
+                );
+                // the device lost callback is left to the app, setting one would replace it
+                (
+                    Arc::clone(adapter),
+                    Arc::clone(device),
+                    Arc::clone(queue),
+                    Arc::new(AtomicBool::new(false)),
+                )
+            }
         };
 
-        let adapter = pollster::block_on(instance.request_adapter(&RequestAdapterOptions {
-            compatible_surface: Some(&surface),
-            ..Default::default()
-        }))
-        .ok_or(WgpuStateError::NoAdapterError)?;
+        let available_adapters = instance.enumerate_adapters(selection.backends).into();
+        let shared = SharedDevice {
+            instance,
+            adapter,
+            available_adapters,
+            device,
+            queue,
+            device_lost,
+            options,
+        };
+        Self::with_surface(&shared, surface)
+    }
 
-        let (device, queue) =
-            pollster::block_on(adapter.request_device(&Default::default(), None))?;
+    /// Creates the state for another surface, drawing with the same device.
+    pub(crate) fn for_surface(
+        shared: &SharedDevice,
+        backend: &Backend,
+        wl_surface: &WlSurface,
+    ) -> Result<Self, WgpuStateError> {
+        let surface = create_surface(&shared.instance, backend, wl_surface)?;
+        if !shared.adapter.is_surface_supported(&surface) {
+            return Err(WgpuStateError::IncompatibleAdapterError);
+        }
+        Self::with_surface(shared, surface)
+    }
+
+    fn with_surface(
+        shared: &SharedDevice,
+        surface: Surface<'static>,
+    ) -> Result<Self, WgpuStateError> {
+        let SharedDevice {
+            instance,
+            adapter,
+            available_adapters,
+            device,
+            queue,
+            device_lost,
+            options,
+        } = shared.clone();
 
         let surface_capabilities = surface.get_capabilities(&adapter);
         let texture_format = surface_capabilities
@@ -74,31 +231,404 @@ impl WgpuState {
             .find(|d| **d == TextureFormat::Bgra8UnormSrgb)
             .ok_or(WgpuStateError::NoTextureFormatError)?;
 
+        // copying out of the surface texture is needed for screenshots, but not supported everywhere
+        let usage = TextureUsages::RENDER_ATTACHMENT
+            | (surface_capabilities.usages & TextureUsages::COPY_SRC);
+
         let surface_configuration = SurfaceConfiguration {
-            usage: TextureUsages::RENDER_ATTACHMENT,
+            usage,
             format: *texture_format,
             width: 1,
             height: 1,
             present_mode: PresentMode::Mailbox,
             desired_maximum_frame_latency: 2,
-            alpha_mode: egui_wgpu::wgpu::CompositeAlphaMode::PreMultiplied,
+            alpha_mode: CompositeAlphaMode::PreMultiplied,
             view_formats: vec![*texture_format],
         };
 
         surface.configure(&device, &surface_configuration);
 
+        if let Some(depth_format) = options.depth_format {
+            let usages = format_features(&adapter, &device, depth_format).allowed_usages;
+            if !depth_format.has_depth_aspect()
+                || !usages.contains(TextureUsages::RENDER_ATTACHMENT)
+            {
+                return Err(WgpuStateError::DepthFormatError(depth_format));
+            }
+        }
+
+        let msaa_samples = supported_msaa_samples(
+            &adapter,
+            &device,
+            *texture_format,
+            options.depth_format,
+            options.msaa_samples,
+        );
+
+        let gpu_timer = GpuTimer::new(&device, &queue);
+
         Ok(Self {
+            instance,
+            adapter,
+            available_adapters,
             device,
             surface_configuration,
             queue,
             surface,
+            msaa_samples,
+            msaa_texture_view: None,
+            depth_texture_view: None,
+            post_render_texture_view: None,
+            gpu_timer,
+            options,
+            device_lost,
         })
     }
 
+    /// The device and everything else other surfaces need to draw with it.
+    pub(crate) fn shared(&self) -> SharedDevice {
+        SharedDevice {
+            instance: Arc::clone(&self.instance),
+            adapter: Arc::clone(&self.adapter),
+            available_adapters: Arc::clone(&self.available_adapters),
+            device: Arc::clone(&self.device),
+            queue: Arc::clone(&self.queue),
+            device_lost: Arc::clone(&self.device_lost),
+            options: self.options.clone(),
+        }
+    }
+
+    /// Access to the wgpu objects for the app, sharing the given egui renderer.
+    pub(crate) fn render_state(&self, renderer: &Arc<RwLock<Renderer>>) -> RenderState {
+        RenderState {
+            adapter: Arc::clone(&self.adapter),
+            available_adapters: Arc::clone(&self.available_adapters),
+            device: Arc::clone(&self.device),
+            queue: Arc::clone(&self.queue),
+            target_format: self.surface_configuration.format,
+            renderer: Arc::clone(renderer),
+        }
+    }
+
+    /// Converts an unmultiplied gamma space color into the clear color for the surface.
+    pub(crate) fn clear_color(&self, rgba: [f32; 4]) -> Color {
+        clear_color(
+            self.surface_configuration.format,
+            self.surface_configuration.alpha_mode,
+            rgba,
+        )
+    }
+
+    /// Whether the device reported itself as lost, e.g. after a GPU reset.
+    pub(crate) fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
+    }
+
+    /// Whether frames can be copied out of the surface texture, otherwise screenshots are
+    /// rendered into a texture from [`WgpuState::create_screenshot_texture`].
+    pub(crate) fn can_copy_surface(&self) -> bool {
+        self.surface_configuration
+            .usage
+            .contains(TextureUsages::COPY_SRC)
+    }
+
+    /// Reconfigures the surface with its current configuration, e.g. after it got lost or outdated.
+    pub(crate) fn reconfigure(&self) {
+        self.surface
+            .configure(&self.device, &self.surface_configuration);
+    }
+
     pub(crate) fn resize(&mut self, width: u32, height: u32) {
         self.surface_configuration.width = width;
         self.surface_configuration.height = height;
         self.surface
             .configure(&self.device, &self.surface_configuration);
+
+        self.msaa_texture_view = (self.msaa_samples > 1).then(|| {
+            self.create_render_target(
+                "egui msaa texture",
+                self.surface_configuration.format,
+                self.msaa_samples,
+                TextureUsages::RENDER_ATTACHMENT,
+            )
+        });
+
+        self.depth_texture_view = self.options.depth_format.map(|depth_format| {
+            self.create_render_target(
+                "egui depth texture",
+                depth_format,
+                self.msaa_samples,
+                TextureUsages::RENDER_ATTACHMENT,
+            )
+        });
+
+        self.post_render_texture_view = self.options.post_render.then(|| {
+            self.create_render_target(
+                "egui post render texture",
+                self.surface_configuration.format,
+                1,
+                TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            )
+        });
+    }
+
+    /// Sample count of the texture egui renders into.
+    pub(crate) fn sample_count(&self) -> u32 {
+        if self.msaa_texture_view.is_some() {
+            self.msaa_samples
+        } else {
+            1
+        }
+    }
+
+    pub(crate) fn depth_format(&self) -> Option<TextureFormat> {
+        self.options.depth_format
+    }
+
+    /// A texture like the surface's which can be copied from, for rendering screenshots into.
+    pub(crate) fn create_screenshot_texture(&self) -> Texture {
+        self.create_texture(
+            "egui screenshot texture",
+            self.surface_configuration.format,
+            1,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        )
+    }
+
+    /// Creates a texture matching the surface size which can be used as render pass attachment.
+    fn create_render_target(
+        &self,
+        label: &str,
+        format: TextureFormat,
+        sample_count: u32,
+        usage: TextureUsages,
+    ) -> TextureView {
+        self.create_texture(label, format, sample_count, usage)
+            .create_view(&Default::default())
+    }
+
+    fn create_texture(
+        &self,
+        label: &str,
+        format: TextureFormat,
+        sample_count: u32,
+        usage: TextureUsages,
+    ) -> Texture {
+        self.device.create_texture(&TextureDescriptor {
+            label: Some(label),
+            size: Extent3d {
+                width: self.surface_configuration.width.max(1),
+                height: self.surface_configuration.height.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: TextureDimension::D2,
+            format,
+            usage,
+            view_formats: &[],
+        })
+    }
+}
+
+fn create_surface(
+    instance: &Instance,
+    backend: &Backend,
+    wl_surface: &WlSurface,
+) -> Result<Surface<'static>, WgpuStateError> {
+    let raw_display_handle = RawDisplayHandle::Wayland(WaylandDisplayHandle::new(
+        NonNull::new(backend.display_ptr() as *mut _).ok_or(WgpuStateError::NullPointerError(
+            "display of backend".to_string(),
+        ))?,
+    ));
+    let raw_window_handle = RawWindowHandle::Wayland(WaylandWindowHandle::new(
+        NonNull::new(wl_surface.id().as_ptr() as *mut _).ok_or(
+            WgpuStateError::NullPointerError("wl_surface id".to_string()),
+        )?,
+    ));
+
+    let surface = unsafe {
+        instance.create_surface_unsafe(SurfaceTargetUnsafe::RawHandle {
+            raw_display_handle,
+            raw_window_handle,
+        })?
+    };
+    Ok(surface)
+}
+
+/// Converts an unmultiplied gamma space color into the clear color for a render target,
+/// taking its format and alpha mode into account.
+pub(crate) fn clear_color(
+    format: TextureFormat,
+    alpha_mode: CompositeAlphaMode,
+    [r, g, b, a]: [f32; 4],
+) -> Color {
+    let [r, g, b] = if format.is_srgb() {
+        // the hardware encodes linear values when writing to srgb formats
+        [r, g, b].map(egui::ecolor::linear_from_gamma)
+    } else {
+        [r, g, b]
+    };
+
+    let [r, g, b, a] = match alpha_mode {
+        CompositeAlphaMode::PreMultiplied => [r * a, g * a, b * a, a],
+        CompositeAlphaMode::Opaque => [r, g, b, 1.0],
+        _ => [r, g, b, a],
+    };
+
+    Color {
+        r: r as f64,
+        g: g as f64,
+        b: b as f64,
+        a: a as f64,
+    }
+}
+
+/// Requests a device with the features needed for the options and watches it for device loss.
+fn request_device(
+    adapter: &Adapter,
+    options: &WgpuOptions,
+) -> Result<(Device, Queue, Arc<AtomicBool>), WgpuStateError> {
+    // without this feature only the sample counts guaranteed by the format are allowed
+    let msaa_features = if options.msaa_samples > 1 {
+        Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+    } else {
+        Features::empty()
+    };
+    // timestamp queries are only used for frame statistics, so they are optional
+    let required_features = adapter.features() & (msaa_features | Features::TIMESTAMP_QUERY);
+
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &DeviceDescriptor {
+            required_features,
+            ..Default::default()
+        },
+        None,
+    ))?;
+
+    let device_lost = Arc::new(AtomicBool::new(false));
+    device.set_device_lost_callback({
+        let device_lost = Arc::clone(&device_lost);
+        move |reason, message| {
+            // dropping or replacing the device also invokes this callback, which is expected
+            if matches!(
+                reason,
+                DeviceLostReason::Unknown | DeviceLostReason::DeviceInvalid
+            ) {
+                log::error!("wgpu device lost ({reason:?}): {message}");
+                device_lost.store(true, Ordering::Release);
+            }
+        }
+    });
+
+    Ok((device, queue, device_lost))
+}
+
+/// Picks an adapter according to the selection options and logs which one was chosen.
+pub(crate) fn request_adapter(
+    instance: &Instance,
+    options: &WgpuOptions,
+    compatible_surface: Option<&Surface>,
+) -> Result<Adapter, WgpuStateError> {
+    let is_compatible = |adapter: &Adapter| {
+        compatible_surface.is_none_or(|surface| adapter.is_surface_supported(surface))
+    };
+
+    let named_adapter = options.adapter_name.as_ref().and_then(|name| {
+        let name = name.to_lowercase();
+        let adapter = instance
+            .enumerate_adapters(options.backends)
+            .into_iter()
+            .filter(|adapter| {
+                !options.force_fallback_adapter || adapter.get_info().device_type == DeviceType::Cpu
+            })
+            .find(|adapter| {
+                adapter.get_info().name.to_lowercase().contains(&name) && is_compatible(adapter)
+            });
+
+        if adapter.is_none() {
+            log::warn!("no compatible adapter matches \"{name}\", using the default selection");
+        }
+        adapter
+    });
+
+    let adapter = match named_adapter {
+        Some(adapter) => adapter,
+        None => pollster::block_on(instance.request_adapter(&RequestAdapterOptions {
+            power_preference: options.power_preference,
+            force_fallback_adapter: options.force_fallback_adapter,
+            compatible_surface,
+        }))
+        .ok_or(WgpuStateError::NoAdapterError)?,
+    };
+
+    log::info!(
+        "using wgpu adapter: {}",
+        egui_wgpu::adapter_info_summary(&adapter.get_info())
+    );
+
+    Ok(adapter)
+}
+
+/// Features of `format`, including adapter specific ones if the device enabled them.
+fn format_features(
+    adapter: &Adapter,
+    device: &Device,
+    format: TextureFormat,
+) -> TextureFormatFeatures {
+    if device
+        .features()
+        .contains(Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+    {
+        adapter.get_texture_format_features(format)
+    } else {
+        format.guaranteed_format_features(device.features())
+    }
+}
+
+/// Returns the highest sample count up to `requested` that can be rendered and resolved
+/// with `format`, and rendered with `depth_format`, on this adapter.
+fn supported_msaa_samples(
+    adapter: &Adapter,
+    device: &Device,
+    format: TextureFormat,
+    depth_format: Option<TextureFormat>,
+    requested: u32,
+) -> u32 {
+    if requested <= 1 {
+        return 1;
+    }
+
+    let color_features = format_features(adapter, device, format);
+    let depth_flags =
+        depth_format.map(|depth_format| format_features(adapter, device, depth_format).flags);
+
+    let supported = if color_features
+        .flags
+        .contains(TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE)
+    {
+        color_features
+            .flags
+            .supported_sample_counts()
+            .into_iter()
+            .filter(|count| *count <= requested)
+            .filter(|count| depth_flags.is_none_or(|flags| flags.sample_count_supported(*count)))
+            .max()
+            .unwrap_or(1)
+    } else {
+        1
+    };
+
+    if supported != requested {
+        match depth_format {
+            Some(depth_format) => log::warn!(
+                "{requested}x msaa is not supported for {format:?} with {depth_format:?}, using {supported}x instead"
+            ),
+            None => log::warn!(
+                "{requested}x msaa is not supported for {format:?}, using {supported}x instead"
+            ),
+        }
     }
+
+    supported
 }